@@ -47,6 +47,14 @@ ui_print "- Extracting module files"
 extract "$ZIPFILE" 'module.prop' "$MODPATH"
 extract "$ZIPFILE" 'uninstall.sh' "$MODPATH"
 
+# Keep the user's config across upgrades
+if [ -f "/data/adb/modules/$MODID/geoink.conf" ]; then
+  ui_print "- Keeping existing geoink.conf"
+  cp -f "/data/adb/modules/$MODID/geoink.conf" "$MODPATH/geoink.conf"
+else
+  extract "$ZIPFILE" 'geoink.conf' "$MODPATH"
+fi
+
 mkdir -p "$MODPATH/zygisk"
 ui_print "- Extracting daemon libraries"
 
@@ -1,11 +1,14 @@
+use std::collections::BTreeSet;
 use std::ffi::CStr;
+use std::os::raw::c_long;
 
 use jni::{
     sys::{jint, JNINativeMethod},
     JNIEnv,
 };
+use regex_lite::Regex;
 
-use crate::binding::{RawApiTable, StateFlags, ZygiskOption};
+use crate::binding::{RawApiTable, RawApiTableV2, RawApiTableV4, StateFlags, ZygiskOption};
 
 /// A handle to API functions provided by the Zygisk runtime. Use this to call utility functions
 /// or to interface with Zygisk.
@@ -21,7 +24,27 @@ use crate::binding::{RawApiTable, StateFlags, ZygiskOption};
 /// To retain this handle across function calls in some rare cases, call the unsafe function
 /// [Self::retain()].
 pub struct ZygiskApi<'a> {
-    inner: &'a RawApiTable,
+    inner: ApiTable<'a>,
+    version: c_long,
+}
+
+/// The API table, in the layout of the version the module registered with.
+#[derive(Clone, Copy)]
+enum ApiTable<'a> {
+    V2(&'a RawApiTableV2),
+    V4(&'a RawApiTableV4),
+}
+
+/// Call an API function that is in the same place in every table layout, or return `$default`
+/// if the runtime didn't provide it.
+macro_rules! call {
+    ($self:ident.$func:ident($($arg:expr),*), $default:expr) => {
+        match $self.inner {
+            ApiTable::V2(table) => table.$func.map(|func| func($($arg),*)),
+            ApiTable::V4(table) => table.$func.map(|func| func($($arg),*)),
+        }
+        .unwrap_or($default)
+    };
 }
 
 impl<'a> ZygiskApi<'a> {
@@ -41,10 +64,7 @@ impl<'a> ZygiskApi<'a> {
     /// Returns a file descriptor to a socket that is connected to the socket passed to your
     /// module's companion request handler. Returns -1 if the connection attempt failed.
     pub fn connect_companion(&self) -> i32 {
-        self.inner
-            .connect_companion
-            .map(|func| func(self.inner.this))
-            .unwrap_or(-1)
+        call!(self.connect_companion(self.this()), -1)
     }
 
     /// Get the file descriptor of the root folder of the current module.
@@ -56,29 +76,21 @@ impl<'a> ZygiskApi<'a> {
     ///
     /// Returns -1 if errors occurred.
     pub fn get_module_dir(&self) -> i32 {
-        self.inner
-            .get_module_dir
-            .map(|func| func(self.inner.this))
-            .unwrap_or(-1)
+        call!(self.get_module_dir(self.this()), -1)
     }
 
     /// Set various options for your module.
     /// Please note that this function accepts one single option at a time.
     /// Check [ZygiskOption] for the full list of options available.
     pub fn set_option(&self, option: ZygiskOption) {
-        if let Some(func) = self.inner.set_option {
-            func(self.inner.this, option);
-        }
+        call!(self.set_option(self.this(), option), ())
     }
 
     /// Get information about the current process.
-    /// Returns bitwise-or'd [StateFlags] values.
+    /// Returns bitwise-or'd [StateFlags] values. Bits this crate doesn't know are kept as they are.
     pub fn get_flags(&self) -> StateFlags {
-        self.inner
-            .get_flags
-            .map(|func| func(self.inner.this))
-            .map(|raw| StateFlags::from_bits(raw).expect("unsupported flag returned by Magisk"))
-            .unwrap_or(StateFlags::empty())
+        let raw = call!(self.get_flags(self.this()), 0);
+        StateFlags::from_raw(raw)
     }
 
     /// Hook JNI native methods for a Java class.
@@ -100,14 +112,15 @@ impl<'a> ZygiskApi<'a> {
         class_name: &CStr, // Change type from &JNIStr to &CStr
         methods: &mut [JNINativeMethod],
     ) {
-        if let Some(func) = self.inner.hook_jni_native_methods {
-            func(
+        call!(
+            self.hook_jni_native_methods(
                 env.get_native_interface(),
                 class_name.as_ptr(),
                 methods.as_mut_ptr(),
-                methods.len() as jint,
-            );
-        }
+                methods.len() as jint
+            ),
+            ()
+        )
     }
 
     /// For ELFs loaded in memory matching `regex`, replace function `symbol` with `new_func`.
@@ -116,6 +129,10 @@ impl<'a> ZygiskApi<'a> {
     ///
     /// If `old_func` is not `None`, the original function pointer will be saved to `old_func`.
     ///
+    /// API v4 dropped this in favor of [Self::plt_hook_register_inode]. On v4 runtimes, `regex`
+    /// is matched against the ELFs currently mapped into the process, and the hook is registered
+    /// for each of them.
+    ///
     /// ## Safety
     ///
     /// This function is unsafe, since a badly designed hook or misuse of raw pointers may lead to
@@ -127,23 +144,66 @@ impl<'a> ZygiskApi<'a> {
         new_func: *mut (),
         old_func: Option<&mut *mut ()>,
     ) {
-        if let Some(func) = self.inner.plt_hook_register {
-            func(
-                regex.as_ptr(),
-                symbol.as_ptr(),
-                new_func,
-                old_func
-                    .map(|r| r as *mut *mut ())
-                    .unwrap_or(std::ptr::null_mut()),
-            );
+        let old_func = old_func.map(|r| r as *mut *mut ()).unwrap_or(std::ptr::null_mut());
+        match self.inner {
+            ApiTable::V2(table) => {
+                if let Some(func) = table.plt_hook_register {
+                    func(regex.as_ptr(), symbol.as_ptr(), new_func, old_func);
+                }
+            }
+            ApiTable::V4(table) => {
+                let (Some(func), Some(elfs)) = (table.plt_hook_register, mapped_elfs(regex)) else {
+                    return;
+                };
+                for (dev, inode) in elfs {
+                    func(dev, inode, symbol.as_ptr(), new_func, old_func);
+                }
+            }
+        }
+    }
+
+    /// For the ELF with device `dev` and inode `inode`, replace function `symbol` with
+    /// `new_func`. Otherwise the same as [Self::plt_hook_register].
+    ///
+    /// This is the native form of PLT hooks since API v4. On older runtimes, the ELF is looked up
+    /// among the ones currently mapped into the process and hooked by its path.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe, since a badly designed hook or misuse of raw pointers may lead to
+    /// memory unsafety.
+    pub unsafe fn plt_hook_register_inode(
+        &self,
+        dev: libc::dev_t,
+        inode: libc::ino_t,
+        symbol: &CStr,
+        new_func: *mut (),
+        old_func: Option<&mut *mut ()>,
+    ) {
+        match self.inner {
+            ApiTable::V2(_) => {
+                let Some(path) = mapped_elf_path(dev, inode) else { return };
+                let Ok(regex) = std::ffi::CString::new(format!("^{}$", regex_lite::escape(&path))) else {
+                    return;
+                };
+                self.plt_hook_register(&regex, symbol, new_func, old_func);
+            }
+            ApiTable::V4(table) => {
+                if let Some(func) = table.plt_hook_register {
+                    let old_func = old_func.map(|r| r as *mut *mut ()).unwrap_or(std::ptr::null_mut());
+                    func(dev, inode, symbol.as_ptr(), new_func, old_func);
+                }
+            }
         }
     }
 
     /// For ELFs loaded in memory matching `regex`, exclude hooks registered for `symbol`.
     ///
     /// If `symbol` is `None`, then all symbols will be excluded.
+    ///
+    /// API v4 removed this, so it does nothing on v4 runtimes.
     pub fn plt_hook_exclude(&self, regex: &CStr, symbol: Option<&CStr>) {
-        if let Some(func) = self.inner.plt_hook_exclude {
+        if let ApiTable::V2(RawApiTableV2 { plt_hook_exclude: Some(func), .. }) = self.inner {
             func(
                 regex.as_ptr(),
                 symbol.map(CStr::as_ptr).unwrap_or(std::ptr::null()),
@@ -151,20 +211,54 @@ impl<'a> ZygiskApi<'a> {
         }
     }
 
+    /// Keep `fd` open in the specialized process. Zygote closes every file descriptor it
+    /// doesn't know about during specialization, so a file opened in `pre[XXX]Specialize` would
+    /// otherwise be gone by the time the app runs.
+    ///
+    /// This API only works in the `pre[XXX]Specialize` functions.
+    ///
+    /// Returns `false` if the fd can't be exempted, which is always the case before API v4.
+    pub fn exempt_fd(&self, fd: i32) -> bool {
+        match self.inner {
+            ApiTable::V2(_) => false,
+            ApiTable::V4(table) => table.exempt_fd.is_some_and(|func| func(fd)),
+        }
+    }
+
     /// Commit all the hooks that was previously registered.
     ///
     /// Returns `false` if any error occurs.
     pub fn plt_hook_commit(&self) -> bool {
-        self.inner
-            .plt_hook_commit
-            .map(|func| func())
-            .unwrap_or(false)
+        call!(self.plt_hook_commit(), false)
+    }
+
+    /// The API version the module registered with, which is the highest one both this binding
+    /// and the runtime support. Functions not available in that version fall back to an
+    /// emulation or fail, as documented on each of them.
+    pub fn api_version(&self) -> c_long {
+        self.version
+    }
+
+    fn this(&self) -> *const () {
+        match self.inner {
+            ApiTable::V2(table) => table.base.this,
+            ApiTable::V4(table) => table.base.this,
+        }
     }
 }
 
 impl<'a> ZygiskApi<'a> {
-    pub(crate) fn from_raw(inner: &'a RawApiTable) -> ZygiskApi<'a> {
-        ZygiskApi { inner }
+    /// Interpret the API table in the layout of `api_version`, the version the module
+    /// registered with.
+    pub(crate) fn from_raw(table: &'a RawApiTable, api_version: c_long) -> ZygiskApi<'a> {
+        let table = table as *const RawApiTable;
+        let inner = unsafe {
+            match api_version {
+                ..=3 => ApiTable::V2(&*table.cast()),
+                _ => ApiTable::V4(&*table.cast()),
+            }
+        };
+        ZygiskApi { inner, version: api_version }
     }
 
     /// Retain the API handle to be used across function calls to [ZygiskModule](crate::ZygiskModule)
@@ -183,3 +277,90 @@ impl<'a> ZygiskApi<'a> {
         std::mem::transmute(self)
     }
 }
+
+/// The `(device, inode)` of every ELF mapped into this process whose path matches `regex`, for
+/// emulating path-based PLT hooks on API v4. `None` if the memory map can't be read.
+///
+/// Only files with executable mappings count, which leaves out fonts, APKs, databases and the
+/// like, as there's no PLT in them.
+pub(crate) fn mapped_elfs(regex: &CStr) -> Option<BTreeSet<(libc::dev_t, libc::ino_t)>> {
+    let regex = Regex::new(regex.to_str().ok()?).ok()?;
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    Some(
+        maps.lines()
+            .filter_map(Mapping::parse)
+            .filter(|m| m.perms.contains('x') && regex.is_match(m.path))
+            .map(|m| (m.dev, m.inode))
+            .collect(),
+    )
+}
+
+/// The path of the ELF with device `dev` and inode `inode`, if it is mapped into this process.
+fn mapped_elf_path(dev: libc::dev_t, inode: libc::ino_t) -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .filter_map(Mapping::parse)
+        .find(|m| (m.dev, m.inode) == (dev, inode))
+        .map(|m| m.path.to_string())
+}
+
+/// The device, inode and path of the file mapped at `addr`, e.g. the ELF a function is in.
+pub(crate) fn mapped_file_at(addr: usize) -> Option<(libc::dev_t, libc::ino_t, String)> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .filter_map(Mapping::parse)
+        .find(|m| m.range.contains(&addr))
+        .map(|m| (m.dev, m.inode, m.path.to_string()))
+}
+
+/// A file-backed line of `/proc/self/maps`.
+#[derive(Debug, PartialEq, Eq)]
+struct Mapping<'a> {
+    range: std::ops::Range<usize>,
+    perms: &'a str,
+    dev: libc::dev_t,
+    inode: libc::ino_t,
+    /// The rest of the line, which may contain spaces, e.g. `/memfd:x (deleted)`.
+    path: &'a str,
+}
+
+impl Mapping<'_> {
+    fn parse(line: &str) -> Option<Mapping<'_>> {
+        // The first five fields are separated by single spaces, the path is padded
+        let mut fields = line.splitn(6, ' ');
+        let (start, end) = fields.next()?.split_once('-')?;
+        let range = usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+        let (perms, _offset) = (fields.next()?, fields.next()?);
+        let (major, minor) = fields.next()?.split_once(':')?;
+        let inode: libc::ino_t = fields.next()?.parse().ok()?;
+        let path = fields.next()?.trim();
+        if inode == 0 || !path.starts_with('/') {
+            return None;
+        }
+        let dev = libc::makedev(u32::from_str_radix(major, 16).ok()?, u32::from_str_radix(minor, 16).ok()?);
+        Some(Mapping { range, perms, dev, inode, path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mappings_are_parsed() {
+        let maps = include_str!("../testdata/maps");
+        let elfs: Vec<_> = maps.lines().filter_map(Mapping::parse).collect();
+        let first = Mapping {
+            range: 0x5f2a3b4000..0x5f2a3b6000,
+            perms: "r--p",
+            dev: libc::makedev(0xfd, 0x05),
+            inode: 1234,
+            path: "/system/bin/app_process64",
+        };
+        assert_eq!(elfs[0], first);
+        assert!(elfs.iter().any(|m| m.path.ends_with("/bionic/libc.so")));
+        assert!(elfs.iter().any(|m| m.path == "/memfd:libgeoink_core.so (deleted)" && m.perms == "r-xp"));
+        // Anonymous and special mappings have no file to hook
+        assert!(elfs.iter().all(|m| m.inode != 0 && !m.path.starts_with('[')));
+    }
+}
@@ -0,0 +1,408 @@
+use std::sync::OnceLock;
+
+use jni::errors::Result;
+use jni::objects::JString;
+use jni::sys::jint;
+use jni::JNIEnv;
+
+use crate::{AppSpecializeArgs, ServerSpecializeArgs};
+
+/// Each Android user gets a range of this many uids, see `UserHandle.PER_USER_RANGE`.
+pub const PER_USER_RANGE: jint = 100000;
+
+/// The mount mode of external storage for an app, the `MOUNT_EXTERNAL_*` constants of `Zygote`.
+///
+/// Zygote renumbered these in Android 12, dropping the modes storage scoping made pointless, so
+/// [from_raw](MountExternal::from_raw) and [to_raw](MountExternal::to_raw) need the SDK level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountExternal {
+    None,
+    Default,
+    /// Until Android 11.
+    Read,
+    /// Until Android 11.
+    Write,
+    /// Android 10 and 11.
+    Legacy,
+    Installer,
+    /// Android 10 and 11.
+    Full,
+    PassThrough,
+    AndroidWritable,
+    /// A value this crate doesn't know.
+    Unknown(jint),
+}
+
+/// The first SDK level with the renumbered [MountExternal] modes.
+const SDK_S: i32 = 31;
+
+impl MountExternal {
+    const LEGACY_MODES: [MountExternal; 9] = [
+        MountExternal::None,
+        MountExternal::Default,
+        MountExternal::Read,
+        MountExternal::Write,
+        MountExternal::Legacy,
+        MountExternal::Installer,
+        MountExternal::Full,
+        MountExternal::PassThrough,
+        MountExternal::AndroidWritable,
+    ];
+    const MODES: [MountExternal; 5] = [
+        MountExternal::None,
+        MountExternal::Default,
+        MountExternal::Installer,
+        MountExternal::PassThrough,
+        MountExternal::AndroidWritable,
+    ];
+
+    fn modes(sdk: i32) -> &'static [MountExternal] {
+        if sdk >= SDK_S {
+            &MountExternal::MODES
+        } else {
+            &MountExternal::LEGACY_MODES
+        }
+    }
+
+    pub fn from_raw(raw: jint, sdk: i32) -> MountExternal {
+        let modes = MountExternal::modes(sdk);
+        usize::try_from(raw).ok().and_then(|i| modes.get(i)).copied().unwrap_or(MountExternal::Unknown(raw))
+    }
+
+    /// The value zygote uses for this mode, or `None` if the mode doesn't exist at that SDK level.
+    pub fn to_raw(self, sdk: i32) -> Option<jint> {
+        match self {
+            MountExternal::Unknown(raw) => Some(raw),
+            mode => MountExternal::modes(sdk).iter().position(|m| *m == mode).map(|i| i as jint),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// The `runtime_flags` zygote passes to a new process, the runtime flag constants of `Zygote`.
+    ///
+    /// Several of these are visible to the app itself, through `ApplicationInfo`, the runtime or
+    /// a JDWP thread showing up, so a debuggable build gives itself away.
+    pub struct RuntimeFlags: u32 {
+        const DEBUG_ENABLE_JDWP = 1 << 0;
+        const DEBUG_ENABLE_CHECKJNI = 1 << 1;
+        const DEBUG_ENABLE_ASSERT = 1 << 2;
+        const DEBUG_ENABLE_SAFEMODE = 1 << 3;
+        const DEBUG_ENABLE_JNI_LOGGING = 1 << 4;
+        const DEBUG_GENERATE_DEBUG_INFO = 1 << 5;
+        const DEBUG_ALWAYS_JIT = 1 << 6;
+        const DEBUG_NATIVE_DEBUGGABLE = 1 << 7;
+        const DEBUG_JAVA_DEBUGGABLE = 1 << 8;
+        const DISABLE_VERIFIER = 1 << 9;
+        const ONLY_USE_SYSTEM_OAT_FILES = 1 << 10;
+        const DEBUG_GENERATE_MINI_DEBUG_INFO = 1 << 11;
+        /// The two bits of the hidden API enforcement policy, see [HiddenApiPolicy].
+        const HIDDEN_API_ENFORCEMENT_POLICY = (1 << 12) | (1 << 13);
+        const PROFILE_SYSTEM_SERVER = 1 << 14;
+        const PROFILE_FROM_SHELL = 1 << 15;
+        const USE_APP_IMAGE_STARTUP_CACHE = 1 << 16;
+        const DEBUG_IGNORE_APP_SIGNAL_HANDLER = 1 << 17;
+        const DISABLE_TEST_API_ENFORCEMENT_POLICY = 1 << 18;
+        const MEMORY_TAG_LEVEL = (1 << 19) | (1 << 20);
+        const GWP_ASAN_LEVEL = (1 << 21) | (1 << 22);
+        const NATIVE_HEAP_ZERO_INIT_ENABLED = 1 << 23;
+        const PROFILEABLE = 1 << 24;
+        const DEBUG_ENABLE_PTRACE = 1 << 25;
+
+        /// Everything that makes an app debuggable.
+        const DEBUGGABLE = Self::DEBUG_ENABLE_JDWP.bits
+            | Self::DEBUG_JAVA_DEBUGGABLE.bits
+            | Self::DEBUG_NATIVE_DEBUGGABLE.bits
+            | Self::DEBUG_ENABLE_PTRACE.bits;
+    }
+}
+
+/// How the runtime treats uses of hidden APIs, `ApplicationInfo.HIDDEN_API_ENFORCEMENT_*`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HiddenApiPolicy {
+    Disabled,
+    JustWarn,
+    Enabled,
+}
+
+const HIDDEN_API_POLICY_SHIFT: u32 = 12;
+
+impl RuntimeFlags {
+    /// The names the config uses for the flags, which are those of the constants.
+    const NAMES: &'static [(&'static str, RuntimeFlags)] = &[
+        ("DEBUG_ENABLE_JDWP", RuntimeFlags::DEBUG_ENABLE_JDWP),
+        ("DEBUG_ENABLE_CHECKJNI", RuntimeFlags::DEBUG_ENABLE_CHECKJNI),
+        ("DEBUG_ENABLE_ASSERT", RuntimeFlags::DEBUG_ENABLE_ASSERT),
+        ("DEBUG_ENABLE_SAFEMODE", RuntimeFlags::DEBUG_ENABLE_SAFEMODE),
+        ("DEBUG_ENABLE_JNI_LOGGING", RuntimeFlags::DEBUG_ENABLE_JNI_LOGGING),
+        ("DEBUG_GENERATE_DEBUG_INFO", RuntimeFlags::DEBUG_GENERATE_DEBUG_INFO),
+        ("DEBUG_ALWAYS_JIT", RuntimeFlags::DEBUG_ALWAYS_JIT),
+        ("DEBUG_NATIVE_DEBUGGABLE", RuntimeFlags::DEBUG_NATIVE_DEBUGGABLE),
+        ("DEBUG_JAVA_DEBUGGABLE", RuntimeFlags::DEBUG_JAVA_DEBUGGABLE),
+        ("DISABLE_VERIFIER", RuntimeFlags::DISABLE_VERIFIER),
+        ("ONLY_USE_SYSTEM_OAT_FILES", RuntimeFlags::ONLY_USE_SYSTEM_OAT_FILES),
+        ("DEBUG_GENERATE_MINI_DEBUG_INFO", RuntimeFlags::DEBUG_GENERATE_MINI_DEBUG_INFO),
+        ("PROFILE_SYSTEM_SERVER", RuntimeFlags::PROFILE_SYSTEM_SERVER),
+        ("PROFILE_FROM_SHELL", RuntimeFlags::PROFILE_FROM_SHELL),
+        ("USE_APP_IMAGE_STARTUP_CACHE", RuntimeFlags::USE_APP_IMAGE_STARTUP_CACHE),
+        ("DEBUG_IGNORE_APP_SIGNAL_HANDLER", RuntimeFlags::DEBUG_IGNORE_APP_SIGNAL_HANDLER),
+        ("DISABLE_TEST_API_ENFORCEMENT_POLICY", RuntimeFlags::DISABLE_TEST_API_ENFORCEMENT_POLICY),
+        ("NATIVE_HEAP_ZERO_INIT_ENABLED", RuntimeFlags::NATIVE_HEAP_ZERO_INIT_ENABLED),
+        ("PROFILEABLE", RuntimeFlags::PROFILEABLE),
+        ("DEBUG_ENABLE_PTRACE", RuntimeFlags::DEBUG_ENABLE_PTRACE),
+        ("DEBUGGABLE", RuntimeFlags::DEBUGGABLE),
+    ];
+
+    /// Keep every bit zygote passed, so that writing the flags back never drops one this crate
+    /// doesn't know about.
+    pub fn from_raw(raw: jint) -> RuntimeFlags {
+        // SAFETY: `RuntimeFlags` is a plain integer, any bits are valid
+        unsafe { RuntimeFlags::from_bits_unchecked(raw as u32) }
+    }
+
+    pub fn to_raw(self) -> jint {
+        self.bits() as jint
+    }
+
+    /// Look a single-bit flag, or `DEBUGGABLE`, up by the name of its constant.
+    pub fn from_name(name: &str) -> Option<RuntimeFlags> {
+        RuntimeFlags::NAMES.iter().find(|(n, _)| *n == name).map(|(_, flag)| *flag)
+    }
+
+    pub fn is_debuggable(&self) -> bool {
+        self.intersects(RuntimeFlags::DEBUGGABLE)
+    }
+
+    /// Whether a JDWP thread is started in the process.
+    pub fn has_jdwp(&self) -> bool {
+        self.contains(RuntimeFlags::DEBUG_ENABLE_JDWP)
+    }
+
+    pub fn hidden_api_policy(&self) -> Option<HiddenApiPolicy> {
+        match (self.bits() & RuntimeFlags::HIDDEN_API_ENFORCEMENT_POLICY.bits()) >> HIDDEN_API_POLICY_SHIFT {
+            0 => Some(HiddenApiPolicy::Disabled),
+            1 => Some(HiddenApiPolicy::JustWarn),
+            2 => Some(HiddenApiPolicy::Enabled),
+            _ => None,
+        }
+    }
+
+    pub fn set_hidden_api_policy(&mut self, policy: HiddenApiPolicy) {
+        let value = match policy {
+            HiddenApiPolicy::Disabled => 0,
+            HiddenApiPolicy::JustWarn => 1,
+            HiddenApiPolicy::Enabled => 2,
+        };
+        self.remove(RuntimeFlags::HIDDEN_API_ENFORCEMENT_POLICY);
+        self.insert(RuntimeFlags::from_raw((value << HIDDEN_API_POLICY_SHIFT) as jint));
+    }
+}
+
+/// The SDK level of the device, read once from `ro.build.version.sdk`.
+///
+/// Off Android this pretends to be the latest release, which is only useful for tests.
+pub fn sdk_level() -> i32 {
+    static SDK: OnceLock<i32> = OnceLock::new();
+    *SDK.get_or_init(read_sdk_level)
+}
+
+#[cfg(target_os = "android")]
+fn read_sdk_level() -> i32 {
+    let mut value = [0; crate::props::PROP_VALUE_MAX];
+    let len = unsafe { libc::__system_property_get(c"ro.build.version.sdk".as_ptr(), value.as_mut_ptr()) };
+    if len <= 0 {
+        return 0;
+    }
+    let value = unsafe { std::ffi::CStr::from_ptr(value.as_ptr()) };
+    value.to_str().ok().and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(not(target_os = "android"))]
+fn read_sdk_level() -> i32 {
+    i32::MAX
+}
+
+/// The package name an app data directory belongs to: `/data/user/0/<package>`,
+/// `/data/data/<package>`, `/mnt/expand/<uuid>/user/10/<package>` and so on.
+pub fn package_from_data_dir(dir: &str) -> Option<&str> {
+    let package = dir.trim_end_matches('/').rsplit('/').next()?;
+    // Package names always have at least two segments
+    (package.contains('.') && !package.starts_with('.')).then_some(package)
+}
+
+/// Split a uid into its Android user id and the app id within that user.
+pub fn split_uid(uid: jint) -> (jint, jint) {
+    (uid / PER_USER_RANGE, uid % PER_USER_RANGE)
+}
+
+/// Read a Java string argument. A null string reads as `None`.
+fn get_string(env: &JNIEnv, string: &JString) -> Option<String> {
+    if string.is_null() {
+        return None;
+    }
+    let string = JString::from(string.into_inner());
+    env.get_string(string).ok().map(String::from)
+}
+
+/// Make a new Java string argument. Zygote only uses the arguments while the JNI call that
+/// specializes the process runs, so the local reference outlives every use.
+fn new_string<'a>(env: &JNIEnv, value: &str) -> Result<JString<'a>> {
+    Ok(JString::from(env.new_string(value)?.into_inner()))
+}
+
+impl<'a> AppSpecializeArgs<'a> {
+    /// The process name, e.g. `com.example.app` or `com.example.app:remote`.
+    pub fn process_name(&self, env: &JNIEnv) -> Option<String> {
+        get_string(env, &*self.nice_name)
+    }
+
+    pub fn set_process_name(&mut self, env: &JNIEnv, name: &str) -> Result<()> {
+        *self.nice_name = new_string(env, name)?;
+        Ok(())
+    }
+
+    /// The package the process runs, taken from its data directory. Processes without one, such
+    /// as isolated services, have `None`.
+    pub fn package_name(&self, env: &JNIEnv) -> Option<String> {
+        let dir = get_string(env, &*self.app_data_dir)?;
+        package_from_data_dir(&dir).map(str::to_string)
+    }
+
+    pub fn app_data_dir(&self, env: &JNIEnv) -> Option<String> {
+        get_string(env, &*self.app_data_dir)
+    }
+
+    pub fn set_app_data_dir(&mut self, env: &JNIEnv, dir: &str) -> Result<()> {
+        *self.app_data_dir = new_string(env, dir)?;
+        Ok(())
+    }
+
+    pub fn se_info(&self, env: &JNIEnv) -> Option<String> {
+        get_string(env, &*self.se_info)
+    }
+
+    /// The Android user the process runs as, 0 for the owner.
+    pub fn user_id(&self) -> jint {
+        split_uid(*self.uid).0
+    }
+
+    /// The uid of the app independent of the user, e.g. 10123.
+    pub fn app_id(&self) -> jint {
+        split_uid(*self.uid).1
+    }
+
+    /// The supplementary groups. A null array reads as empty.
+    pub fn gids(&self, env: &JNIEnv) -> Result<Vec<jint>> {
+        if self.gids.is_null() {
+            return Ok(Vec::new());
+        }
+        let mut gids = vec![0; env.get_array_length(*self.gids)? as usize];
+        env.get_int_array_region(*self.gids, 0, &mut gids)?;
+        Ok(gids)
+    }
+
+    /// Replace the supplementary groups with a new array, so the caller's array is left alone.
+    pub fn set_gids(&mut self, env: &JNIEnv, gids: &[jint]) -> Result<()> {
+        let array = env.new_int_array(gids.len() as jint)?;
+        env.set_int_array_region(array, 0, gids)?;
+        *self.gids = array;
+        Ok(())
+    }
+
+    pub fn runtime_flags(&self) -> RuntimeFlags {
+        RuntimeFlags::from_raw(*self.runtime_flags)
+    }
+
+    pub fn set_runtime_flags(&mut self, flags: RuntimeFlags) {
+        *self.runtime_flags = flags.to_raw();
+    }
+
+    /// Remove `flags` from the runtime flags, returning those that were actually set.
+    pub fn clear_runtime_flags(&mut self, flags: RuntimeFlags) -> RuntimeFlags {
+        let current = self.runtime_flags();
+        self.set_runtime_flags(current - flags);
+        current & flags
+    }
+
+    pub fn mount_external(&self) -> MountExternal {
+        MountExternal::from_raw(*self.mount_external, sdk_level())
+    }
+
+    /// Set the external storage mount mode, returning `false` (and changing nothing) if the
+    /// mode doesn't exist on this Android version.
+    pub fn set_mount_external(&mut self, mode: MountExternal) -> bool {
+        match mode.to_raw(sdk_level()) {
+            Some(raw) => {
+                *self.mount_external = raw;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a> ServerSpecializeArgs<'a> {
+    pub fn runtime_flags(&self) -> RuntimeFlags {
+        RuntimeFlags::from_raw(*self.runtime_flags)
+    }
+
+    pub fn set_runtime_flags(&mut self, flags: RuntimeFlags) {
+        *self.runtime_flags = flags.to_raw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packages_from_data_dirs() {
+        assert_eq!(package_from_data_dir("/data/user/0/com.example.app"), Some("com.example.app"));
+        assert_eq!(package_from_data_dir("/data/data/com.example.app/"), Some("com.example.app"));
+        assert_eq!(package_from_data_dir("/mnt/expand/0f3c-11/user/10/org.app"), Some("org.app"));
+        assert_eq!(package_from_data_dir("/data/user/0"), None);
+        assert_eq!(package_from_data_dir(""), None);
+    }
+
+    #[test]
+    fn uids_split_into_user_and_app() {
+        assert_eq!(split_uid(10123), (0, 10123));
+        assert_eq!(split_uid(1010123), (10, 10123));
+        assert_eq!(split_uid(1000), (0, 1000));
+    }
+
+    #[test]
+    fn mount_modes_depend_on_the_sdk() {
+        assert_eq!(MountExternal::from_raw(2, 30), MountExternal::Read);
+        assert_eq!(MountExternal::from_raw(2, 31), MountExternal::Installer);
+        assert_eq!(MountExternal::from_raw(7, 30), MountExternal::PassThrough);
+        assert_eq!(MountExternal::from_raw(7, 33), MountExternal::Unknown(7));
+        assert_eq!(MountExternal::from_raw(-1, 33), MountExternal::Unknown(-1));
+
+        assert_eq!(MountExternal::PassThrough.to_raw(30), Some(7));
+        assert_eq!(MountExternal::PassThrough.to_raw(34), Some(3));
+        assert_eq!(MountExternal::Full.to_raw(34), None);
+        assert_eq!(MountExternal::Unknown(12).to_raw(34), Some(12));
+    }
+
+    #[test]
+    fn runtime_flags_round_trip() {
+        // JDWP, Java debuggable, hidden API policy "enabled", and a bit from the future
+        let raw = (1 << 0) | (1 << 8) | (2 << 12) | (1 << 30);
+        let mut flags = RuntimeFlags::from_raw(raw);
+        assert!(flags.is_debuggable() && flags.has_jdwp());
+        assert_eq!(flags.hidden_api_policy(), Some(HiddenApiPolicy::Enabled));
+
+        flags.remove(RuntimeFlags::DEBUGGABLE);
+        assert!(!flags.is_debuggable());
+        assert_eq!(flags.to_raw(), (2 << 12) | (1 << 30));
+
+        flags.set_hidden_api_policy(HiddenApiPolicy::JustWarn);
+        assert_eq!(flags.to_raw(), (1 << 12) | (1 << 30));
+        assert_eq!(RuntimeFlags::from_raw(3 << 12).hidden_api_policy(), None);
+
+        assert_eq!(RuntimeFlags::from_name("DEBUG_ENABLE_JDWP"), Some(RuntimeFlags::DEBUG_ENABLE_JDWP));
+        assert_eq!(RuntimeFlags::from_name("DEBUGGABLE"), Some(RuntimeFlags::DEBUGGABLE));
+        assert_eq!(RuntimeFlags::from_name("debug_enable_jdwp"), None);
+    }
+}
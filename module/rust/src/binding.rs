@@ -6,23 +6,36 @@ use jni::{objects::JString, sys::*};
 type c_bool = bool;
 type Module = crate::module::RawModule;
 
-pub const API_VERSION: c_long = 2;
+/// The highest Zygisk API version this binding implements.
+pub const API_VERSION: c_long = 5;
+
+/// Every API version this binding implements, in the order they are tried when registering.
+pub(crate) const SUPPORTED_API_VERSIONS: &[c_long] = &[5, 4, 2];
 
 #[repr(C)]
 pub(crate) struct ModuleAbi {
     pub api_version: c_long,
     pub this: &'static mut Module,
-    pub pre_app_specialize: extern "C" fn(&mut Module, &mut AppSpecializeArgs),
-    pub post_app_specialize: extern "C" fn(&mut Module, &AppSpecializeArgs),
+    // The layout of the arguments depends on `api_version`, see [AppSpecializeArgs::from_raw]
+    pub pre_app_specialize: extern "C" fn(&mut Module, *mut c_void),
+    pub post_app_specialize: extern "C" fn(&mut Module, *const c_void),
     pub pre_server_specialize: extern "C" fn(&mut Module, &mut ServerSpecializeArgs),
     pub post_server_specialize: extern "C" fn(&mut Module, &ServerSpecializeArgs),
 }
 
+/// The start of the API table, which is the same in every API version. The runtime fills in
+/// the rest when a module registers, laid out according to the version it registered with.
 #[repr(C)]
 pub(crate) struct RawApiTable {
     // These first 2 entries are permanent, shall never change across API versions
     pub this: *const (),
     pub register_module: Option<extern "C" fn(*const RawApiTable, *mut ModuleAbi) -> c_bool>,
+}
+
+/// The API table of v2 (and v3).
+#[repr(C)]
+pub(crate) struct RawApiTableV2 {
+    pub base: RawApiTable,
 
     // Utility functions
     pub hook_jni_native_methods:
@@ -39,13 +52,39 @@ pub(crate) struct RawApiTable {
     pub get_flags: Option<extern "C" fn(*const ()) -> u32>,
 }
 
+/// The API table of v4 and v5, where PLT hooks target an ELF by device and inode instead of a
+/// path regex, `plt_hook_exclude` is gone and `exempt_fd` is new.
 #[repr(C)]
+pub(crate) struct RawApiTableV4 {
+    pub base: RawApiTable,
+
+    // Utility functions
+    pub hook_jni_native_methods:
+        Option<extern "C" fn(*mut JNIEnv, *const c_char, *mut JNINativeMethod, c_int)>,
+    pub plt_hook_register:
+        Option<extern "C" fn(libc::dev_t, libc::ino_t, *const c_char, *mut (), *mut *mut ())>,
+    pub exempt_fd: Option<extern "C" fn(c_int) -> c_bool>,
+    pub plt_hook_commit: Option<extern "C" fn() -> c_bool>,
+
+    // Zygisk functions
+    pub connect_companion: Option<extern "C" fn(*const ()) -> c_int>,
+    pub set_option: Option<extern "C" fn(*const (), ZygiskOption)>,
+    pub get_module_dir: Option<extern "C" fn(*const ()) -> c_int>,
+    pub get_flags: Option<extern "C" fn(*const ()) -> u32>,
+}
+
+/// The arguments of app specialization.
+///
+/// This is built from whichever layout the runtime passes for the negotiated API version.
+/// Arguments that only exist in newer versions are `None` on older runtimes.
 pub struct AppSpecializeArgs<'a> {
     // Required arguments. These arguments are guaranteed to exist on all Android versions.
     pub uid: &'a mut jint,
     pub gid: &'a mut jint,
     pub gids: &'a mut jintArray,
     pub runtime_flags: &'a mut jint,
+    /// Only passed by API v3 and later.
+    pub rlimits: Option<&'a mut jobjectArray>,
     pub mount_external: &'a mut jint,
     pub se_info: &'a mut JString<'a>,
     pub nice_name: &'a mut JString<'a>,
@@ -53,6 +92,8 @@ pub struct AppSpecializeArgs<'a> {
     pub app_data_dir: &'a mut JString<'a>,
 
     // Optional arguments. Please check whether the pointer is null before de-referencing
+    /// Only passed by API v3 and later.
+    pub fds_to_ignore: Option<&'a jintArray>,
     pub is_child_zygote: Option<&'a jboolean>,
     pub is_top_app: Option<&'a jboolean>,
     pub pkg_data_info_list: Option<&'a jobjectArray>,
@@ -61,6 +102,105 @@ pub struct AppSpecializeArgs<'a> {
     pub mount_storage_dirs: Option<&'a jboolean>,
 }
 
+/// `AppSpecializeArgs` as laid out by API v2.
+#[repr(C)]
+struct AppSpecializeArgsV2<'a> {
+    uid: &'a mut jint,
+    gid: &'a mut jint,
+    gids: &'a mut jintArray,
+    runtime_flags: &'a mut jint,
+    mount_external: &'a mut jint,
+    se_info: &'a mut JString<'a>,
+    nice_name: &'a mut JString<'a>,
+    instruction_set: &'a mut JString<'a>,
+    app_data_dir: &'a mut JString<'a>,
+
+    is_child_zygote: Option<&'a jboolean>,
+    is_top_app: Option<&'a jboolean>,
+    pkg_data_info_list: Option<&'a jobjectArray>,
+    whitelisted_data_info_list: Option<&'a jobjectArray>,
+    mount_data_dirs: Option<&'a jboolean>,
+    mount_storage_dirs: Option<&'a jboolean>,
+}
+
+/// `AppSpecializeArgs` as laid out by API v3 and later, which added `rlimits` and `fds_to_ignore`.
+#[repr(C)]
+struct AppSpecializeArgsV3<'a> {
+    uid: &'a mut jint,
+    gid: &'a mut jint,
+    gids: &'a mut jintArray,
+    runtime_flags: &'a mut jint,
+    rlimits: &'a mut jobjectArray,
+    mount_external: &'a mut jint,
+    se_info: &'a mut JString<'a>,
+    nice_name: &'a mut JString<'a>,
+    instruction_set: &'a mut JString<'a>,
+    app_data_dir: &'a mut JString<'a>,
+
+    fds_to_ignore: Option<&'a jintArray>,
+    is_child_zygote: Option<&'a jboolean>,
+    is_top_app: Option<&'a jboolean>,
+    pkg_data_info_list: Option<&'a jobjectArray>,
+    whitelisted_data_info_list: Option<&'a jobjectArray>,
+    mount_data_dirs: Option<&'a jboolean>,
+    mount_storage_dirs: Option<&'a jboolean>,
+}
+
+impl<'a> AppSpecializeArgs<'a> {
+    /// Read the arguments the runtime passed, laid out for `api_version`.
+    ///
+    /// ## Safety
+    ///
+    /// `args` must point to the runtime's arguments for that API version, which must outlive `'a`.
+    pub(crate) unsafe fn from_raw(args: *const c_void, api_version: c_long) -> AppSpecializeArgs<'a> {
+        // Both layouts only hold references, so copying them out still refers to the runtime's
+        // values, and writes through them take effect
+        if api_version >= 3 {
+            let raw = std::ptr::read(args as *const AppSpecializeArgsV3);
+            AppSpecializeArgs {
+                uid: raw.uid,
+                gid: raw.gid,
+                gids: raw.gids,
+                runtime_flags: raw.runtime_flags,
+                rlimits: Some(raw.rlimits),
+                mount_external: raw.mount_external,
+                se_info: raw.se_info,
+                nice_name: raw.nice_name,
+                instruction_set: raw.instruction_set,
+                app_data_dir: raw.app_data_dir,
+                fds_to_ignore: raw.fds_to_ignore,
+                is_child_zygote: raw.is_child_zygote,
+                is_top_app: raw.is_top_app,
+                pkg_data_info_list: raw.pkg_data_info_list,
+                whitelisted_data_info_list: raw.whitelisted_data_info_list,
+                mount_data_dirs: raw.mount_data_dirs,
+                mount_storage_dirs: raw.mount_storage_dirs,
+            }
+        } else {
+            let raw = std::ptr::read(args as *const AppSpecializeArgsV2);
+            AppSpecializeArgs {
+                uid: raw.uid,
+                gid: raw.gid,
+                gids: raw.gids,
+                runtime_flags: raw.runtime_flags,
+                rlimits: None,
+                mount_external: raw.mount_external,
+                se_info: raw.se_info,
+                nice_name: raw.nice_name,
+                instruction_set: raw.instruction_set,
+                app_data_dir: raw.app_data_dir,
+                fds_to_ignore: None,
+                is_child_zygote: raw.is_child_zygote,
+                is_top_app: raw.is_top_app,
+                pkg_data_info_list: raw.pkg_data_info_list,
+                whitelisted_data_info_list: raw.whitelisted_data_info_list,
+                mount_data_dirs: raw.mount_data_dirs,
+                mount_storage_dirs: raw.mount_storage_dirs,
+            }
+        }
+    }
+}
+
 #[repr(C)]
 pub struct ServerSpecializeArgs<'a> {
     pub uid: &'a mut jint,
@@ -71,9 +211,8 @@ pub struct ServerSpecializeArgs<'a> {
     pub effective_capabilities: &'a mut jlong,
 }
 
-/// Zygisk module options, used in [ZygiskApi::set_option()](crate::ZygiskApi::set_option).
-
 // Note: the original definition is `enum Option : int`. This is a best-effort approach.
+/// Zygisk module options, used in [ZygiskApi::set_option()](crate::ZygiskApi::set_option).
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ZygiskOption {
@@ -101,5 +240,85 @@ bitflags::bitflags! {
 
         /// The current process was added on the denylist.
         const PROCESS_ON_DENYLIST = (1 << 1);
+
+        /// The current process is the root manager app.
+        const PROCESS_IS_MANAGER = (1 << 27);
+
+        /// The device is rooted with APatch.
+        const PROCESS_ROOT_IS_APATCH = (1 << 28);
+
+        /// The device is rooted with KernelSU.
+        const PROCESS_ROOT_IS_KSU = (1 << 29);
+
+        /// The device is rooted with Magisk.
+        const PROCESS_ROOT_IS_MAGISK = (1 << 30);
+
+        /// This is the first process zygote started.
+        const PROCESS_IS_FIRST_STARTED = (1 << 31);
+    }
+}
+
+/// The root implementation the Zygisk runtime runs on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootImpl {
+    Magisk,
+    KernelSU,
+    APatch,
+}
+
+impl StateFlags {
+    /// Keep every bit of a value returned by the runtime, including those this crate doesn't
+    /// know about yet, so that a newer runtime can't make `get_flags` fail.
+    pub(crate) fn from_raw(raw: u32) -> StateFlags {
+        // SAFETY: `StateFlags` is a plain integer, any bits are valid
+        unsafe { StateFlags::from_bits_unchecked(raw) }
+    }
+
+    /// The bits that are set but aren't any of the known flags.
+    pub fn unknown_bits(&self) -> u32 {
+        self.bits() & !StateFlags::all().bits()
+    }
+
+    /// Which root implementation the runtime says it runs on, if it says.
+    ///
+    /// Only the standalone Zygisk implementations (Zygisk Next, ReZygisk, ...) set these flags;
+    /// Magisk's built-in Zygisk doesn't, so `None` doesn't rule Magisk out.
+    pub fn root_impl(&self) -> Option<RootImpl> {
+        let found = [
+            (StateFlags::PROCESS_ROOT_IS_MAGISK, RootImpl::Magisk),
+            (StateFlags::PROCESS_ROOT_IS_KSU, RootImpl::KernelSU),
+            (StateFlags::PROCESS_ROOT_IS_APATCH, RootImpl::APatch),
+        ];
+        let mut found = found.into_iter().filter(|(flag, _)| self.contains(*flag)).map(|(_, root)| root);
+        // More than one would be a runtime bug, and not something to guess about
+        match (found.next(), found.next()) {
+            (Some(root), None) => Some(root),
+            _ => None,
+        }
+    }
+
+    /// Whether the current process is the root manager app.
+    pub fn is_root_manager(&self) -> bool {
+        self.contains(StateFlags::PROCESS_IS_MANAGER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_state_flags_are_kept() {
+        let flags = StateFlags::from_raw((1 << 0) | (1 << 5) | (1 << 29));
+        assert!(flags.contains(StateFlags::PROCESS_GRANTED_ROOT));
+        assert_eq!(flags.unknown_bits(), 1 << 5);
+        assert_eq!(flags.bits(), (1 << 0) | (1 << 5) | (1 << 29));
+        assert_eq!(flags.root_impl(), Some(RootImpl::KernelSU));
+        assert!(!flags.is_root_manager());
+
+        assert_eq!(StateFlags::from_raw(0).root_impl(), None);
+        let both = StateFlags::PROCESS_ROOT_IS_MAGISK | StateFlags::PROCESS_ROOT_IS_APATCH;
+        assert_eq!(both.root_impl(), None);
+        assert!(StateFlags::from_raw(1 << 27).is_root_manager());
     }
 }
@@ -0,0 +1,78 @@
+use jni::objects::JValue;
+use jni::JNIEnv;
+
+use crate::events::{self, Action};
+
+/// A static field of `android.os.Build` or `Build.VERSION` to overwrite, written in the config
+/// as `<FIELD> <value>`, e.g. `TAGS release-keys` or `VERSION.SECURITY_PATCH 2024-05-01`.
+///
+/// The field's type is looked up when it is set: `String`, `int`, `long` and `boolean` fields
+/// are supported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildField {
+    pub name: String,
+    pub value: String,
+}
+
+impl BuildField {
+    pub fn parse(value: &str) -> Result<BuildField, String> {
+        let (name, value) = value.split_once(char::is_whitespace).ok_or("expected `<FIELD> <value>`")?;
+        let field = name.strip_prefix("VERSION.").unwrap_or(name);
+        if field.is_empty() || !field.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_') {
+            return Err(format!("`{}` is not a Build field", name));
+        }
+        Ok(BuildField { name: name.to_string(), value: value.trim().to_string() })
+    }
+
+    fn class_and_field(&self) -> (&'static str, &str) {
+        match self.name.strip_prefix("VERSION.") {
+            Some(field) => ("android/os/Build$VERSION", field),
+            None => ("android/os/Build", &self.name),
+        }
+    }
+}
+
+/// Overwrite the configured `Build` fields.
+///
+/// Apps read these from Java, and they were filled in from the real properties when zygote
+/// preloaded the class, so the property hooks never get a say.
+pub fn apply(env: &JNIEnv, fields: &[BuildField]) {
+    for field in fields {
+        match set(env, field) {
+            Ok(()) => {
+                info!("Spoofed Build.{} -> {}", field.name, field.value);
+                events::record("Build", Action::Spoof, field.name.as_str());
+            }
+            Err(e) => {
+                let _ = env.exception_clear();
+                error!("Failed to spoof Build.{}: {}", field.name, e);
+            }
+        }
+    }
+}
+
+fn set(env: &JNIEnv, field: &BuildField) -> Result<(), String> {
+    let (class, name) = field.class_and_field();
+    let class = env.find_class(class).map_err(|e| e.to_string())?;
+    let bad_value = |ty: &str| format!("`{}` is not a valid {}", field.value, ty);
+
+    // The config doesn't say what type a field is, so ask the class
+    for sig in ["Ljava/lang/String;", "I", "J", "Z"] {
+        let Ok(id) = env.get_static_field_id(class, name, sig) else {
+            let _ = env.exception_clear();
+            continue;
+        };
+        let value = match sig {
+            "I" => JValue::Int(field.value.parse().map_err(|_| bad_value("int"))?),
+            "J" => JValue::Long(field.value.parse().map_err(|_| bad_value("long"))?),
+            "Z" => JValue::Bool(match field.value.as_str() {
+                "true" | "1" => 1,
+                "false" | "0" => 0,
+                _ => return Err(bad_value("boolean")),
+            }),
+            _ => JValue::Object(env.new_string(&field.value).map_err(|e| e.to_string())?.into()),
+        };
+        return env.set_static_field(class, id, value).map_err(|e| e.to_string());
+    }
+    Err("no such field".to_string())
+}
@@ -0,0 +1,886 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::{
+    build_fields::BuildField,
+    events::{Action, Event},
+    logging::{LogBackend, LogLine},
+    mounts::{MountField, MountRule},
+    paths::{PathMatch, PathRule},
+    profile::Profile,
+    props::{PropAction, PropMatch, PropRule},
+    RuntimeFlags, ZygiskApi,
+};
+
+/// Sent first by both sides, so a stray connection is told apart from a real peer.
+const MAGIC: [u8; 4] = *b"GINK";
+
+/// The version of the message format below. Bump it whenever a message is added, removed or
+/// changed, so that a module and a companion from different builds fail the handshake rather
+/// than a request halfway through a session.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Frames larger than this are refused rather than allocated.
+pub const MAX_FRAME_LEN: usize = 4 << 20;
+
+/// The most fds that can be sent with one message.
+pub const MAX_FDS: usize = 16;
+
+/// How long the app side waits for the companion. The app isn't started until
+/// `pre[XXX]Specialize` returns, so this needs to be short.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the companion waits for the next request before giving up on the connection.
+pub const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something that went wrong talking to the other side.
+#[derive(Debug)]
+pub enum CompanionError {
+    /// The runtime couldn't connect to the companion.
+    Unavailable,
+    /// The other side took longer than the timeout.
+    Timeout,
+    Io(io::Error),
+    /// The other side speaks a different protocol version.
+    VersionMismatch { ours: u32, theirs: u32 },
+    /// The other side sent something that isn't a valid message.
+    Protocol(String),
+    /// The companion answered with [Response::Error].
+    Remote(String),
+}
+
+impl fmt::Display for CompanionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompanionError::Unavailable => write!(f, "companion unavailable"),
+            CompanionError::Timeout => write!(f, "companion timed out"),
+            CompanionError::Io(e) => write!(f, "{}", e),
+            CompanionError::VersionMismatch { ours, theirs } => {
+                write!(f, "protocol version {} but the other side has {}", ours, theirs)
+            }
+            CompanionError::Protocol(msg) => write!(f, "bad message: {}", msg),
+            CompanionError::Remote(msg) => write!(f, "companion error: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for CompanionError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // A read timeout surfaces as EAGAIN
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => CompanionError::Timeout,
+            _ => CompanionError::Io(e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, CompanionError>;
+
+/// A request from a module instance to its companion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    /// The effective profile for the process named, if any.
+    GetProfile { process: String },
+    /// Events recorded by a process, to be persisted. `dropped` counts those that didn't fit in
+    /// its buffer.
+    Events { pid: i32, process: String, dropped: u64, events: Vec<Event> },
+    /// Lines logged by a process through the `companion` backend, to be persisted.
+    Log { pid: i32, process: String, lines: Vec<LogLine> },
+}
+
+/// The companion's answer to a [Request].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    Pong,
+    /// The effective profile, resolved from the config by the companion so that app processes
+    /// don't parse it. `None` if no profile covers the process.
+    Profile(Option<Profile>),
+    /// The request was carried out, and there is nothing to answer.
+    Done,
+    /// The request couldn't be served. Clients turn this into [CompanionError::Remote].
+    Error(String),
+}
+
+impl Request {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Request::Ping => w.u8(0),
+            Request::GetProfile { process } => {
+                w.u8(1);
+                w.str(process);
+            }
+            Request::Events { pid, process, dropped, events } => {
+                w.u8(2);
+                w.u32(*pid as u32);
+                w.str(process);
+                w.u64(*dropped);
+                w.u32(events.len() as u32);
+                for event in events {
+                    w.u64(event.time);
+                    w.str(&event.hook);
+                    w.str(event.action.name());
+                    w.str(&event.subject);
+                }
+            }
+            Request::Log { pid, process, lines } => {
+                w.u8(3);
+                w.u32(*pid as u32);
+                w.str(process);
+                w.u32(lines.len() as u32);
+                for line in lines {
+                    w.u64(line.time);
+                    w.str(&line.text);
+                }
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Request> {
+        match r.u8()? {
+            0 => Ok(Request::Ping),
+            1 => Ok(Request::GetProfile { process: r.str()? }),
+            2 => {
+                let (pid, process, dropped) = (r.u32()? as i32, r.str()?, r.u64()?);
+                let count = r.u32()?;
+                let mut events = Vec::new();
+                for _ in 0..count {
+                    let (time, hook, action) = (r.u64()?, r.str()?, r.str()?);
+                    let action = Action::from_name(&action)
+                        .ok_or_else(|| CompanionError::Protocol(format!("unknown action {}", action)))?;
+                    events.push(Event { time, hook: hook.into(), action, subject: r.str()? });
+                }
+                Ok(Request::Events { pid, process, dropped, events })
+            }
+            3 => {
+                let (pid, process) = (r.u32()? as i32, r.str()?);
+                let count = r.u32()?;
+                let mut lines = Vec::new();
+                for _ in 0..count {
+                    lines.push(LogLine { time: r.u64()?, text: r.str()? });
+                }
+                Ok(Request::Log { pid, process, lines })
+            }
+            tag => Err(CompanionError::Protocol(format!("unknown request {}", tag))),
+        }
+    }
+}
+
+impl Response {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Response::Pong => w.u8(0),
+            Response::Error(msg) => {
+                w.u8(1);
+                w.str(msg);
+            }
+            Response::Profile(profile) => {
+                w.u8(2);
+                w.opt(profile.as_ref(), Writer::profile);
+            }
+            Response::Done => w.u8(3),
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Response> {
+        match r.u8()? {
+            0 => Ok(Response::Pong),
+            1 => Ok(Response::Error(r.str()?)),
+            2 => Ok(Response::Profile(r.opt(Reader::profile)?)),
+            3 => Ok(Response::Done),
+            tag => Err(CompanionError::Protocol(format!("unknown response {}", tag))),
+        }
+    }
+}
+
+/// A [Response] and the fds sent along with it.
+#[derive(Debug)]
+pub struct Reply {
+    pub response: Response,
+    pub fds: Vec<OwnedFd>,
+}
+
+impl Reply {
+    pub fn with_fds(response: Response, fds: Vec<OwnedFd>) -> Reply {
+        Reply { response, fds }
+    }
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Reply {
+        Reply { response, fds: Vec::new() }
+    }
+}
+
+/// Send `data` over a Unix socket with `fds` attached as `SCM_RIGHTS`, returning how many bytes
+/// were sent. The fds arrive with the first of those bytes; `data` must not be empty, as there
+/// is nothing to attach them to otherwise.
+///
+/// The fds stay open on this side, the receiver gets its own copies.
+pub fn send_fds(socket: BorrowedFd, data: &[u8], fds: &[BorrowedFd]) -> io::Result<usize> {
+    if data.is_empty() || fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad message for SCM_RIGHTS"));
+    }
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let mut control = ControlBuffer::new();
+    if !fds.is_empty() {
+        let fds_len = mem::size_of_val(fds) as u32;
+        msg.msg_control = control.as_mut_ptr();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, fd) in fds.iter().enumerate() {
+                data.add(i).write_unaligned(fd.as_raw_fd());
+            }
+        }
+    }
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Receive data from a Unix socket into `buf`, along with any fds attached to it, which are
+/// opened close-on-exec. Returns 0 bytes at the end of the stream.
+pub fn recv_fds(socket: BorrowedFd, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    let mut control = ControlBuffer::new();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr();
+    msg.msg_controllen = ControlBuffer::LEN as _;
+
+    let received = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Take ownership of everything that arrived first, so nothing leaks on the error below
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many fds received"));
+    }
+    Ok((received as usize, fds))
+}
+
+/// Room for the `SCM_RIGHTS` header and [MAX_FDS] fds, aligned for `cmsghdr`.
+struct ControlBuffer([u64; ControlBuffer::LEN.div_ceil(8)]);
+
+impl ControlBuffer {
+    const LEN: usize = mem::size_of::<libc::cmsghdr>() + MAX_FDS * mem::size_of::<RawFd>() + 8;
+
+    fn new() -> ControlBuffer {
+        ControlBuffer([0; ControlBuffer::LEN.div_ceil(8)])
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut libc::c_void {
+        self.0.as_mut_ptr().cast()
+    }
+}
+
+/// The wire tags of the enums in a [Profile], both ways.
+macro_rules! tags {
+    ($($module:ident: $ty:ty { $($variant:path = $tag:literal),* $(,)? })*) => {
+        $(
+            mod $module {
+                use super::*;
+
+                pub fn tag(value: $ty) -> u8 {
+                    match value {
+                        $($variant => $tag,)*
+                    }
+                }
+
+                pub fn from_tag(tag: u8) -> Result<$ty> {
+                    match tag {
+                        $($tag => Ok($variant),)*
+                        _ => {
+                            let msg = format!(concat!("unknown ", stringify!($module), " {}"), tag);
+                            Err(CompanionError::Protocol(msg))
+                        }
+                    }
+                }
+            }
+        )*
+    };
+}
+
+tags! {
+    path_match: PathMatch {
+        PathMatch::Exact = 0,
+        PathMatch::Prefix = 1,
+        PathMatch::Glob = 2,
+        PathMatch::Basename = 3,
+    }
+    mount_field: MountField {
+        MountField::Source = 0,
+        MountField::Target = 1,
+        MountField::FsType = 2,
+        MountField::Root = 3,
+    }
+    prop_match: PropMatch { PropMatch::Exact = 0, PropMatch::Prefix = 1, PropMatch::Regex = 2 }
+    log_backend: LogBackend {
+        LogBackend::Off = 0,
+        LogBackend::Logcat = 1,
+        LogBackend::Companion = 2,
+        LogBackend::File = 3,
+    }
+}
+
+/// Builds the body of a frame. Integers are little endian, strings are length prefixed.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+    }
+
+    fn str(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    fn opt<T>(&mut self, v: Option<T>, value: impl FnOnce(&mut Self, T)) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                value(self, v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn list<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Self, &T)) {
+        self.u32(items.len() as u32);
+        for i in items {
+            item(self, i);
+        }
+    }
+
+    fn profile(&mut self, p: &Profile) {
+        self.str(&p.pattern);
+        for paths in [&p.hidden_paths, &p.allowed_paths] {
+            self.list(paths, |w, rule| {
+                w.u8(path_match::tag(rule.kind));
+                w.str(&rule.pattern);
+                w.u32(rule.errno as u32);
+                w.u32(rule.child_errno as u32);
+            });
+        }
+        self.list(&p.hidden_maps, |w, regex| w.str(regex));
+        self.list(&p.hidden_mounts, |w, rule| {
+            w.u8(mount_field::tag(rule.field));
+            w.str(&rule.pattern);
+        });
+        self.list(&p.props, |w, rule| {
+            w.u8(prop_match::tag(rule.kind));
+            w.str(&rule.pattern);
+            match &rule.action {
+                PropAction::Spoof(value) => {
+                    w.u8(0);
+                    w.str(value);
+                }
+                PropAction::Hide => w.u8(1),
+                PropAction::Passthrough => w.u8(2),
+            }
+        });
+        self.list(&p.build_fields, |w, field| {
+            w.str(&field.name);
+            w.str(&field.value);
+        });
+        self.list(&p.cleared_runtime_flags, |w, flags| w.u32(flags.to_raw() as u32));
+        self.list(&p.blocked_packages, |w, package| w.str(package));
+        self.opt(p.log, |w, backend| w.u8(log_backend::tag(backend)));
+    }
+}
+
+/// Reads back what [Writer] wrote, failing on truncated input rather than panicking.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(CompanionError::Protocol("truncated message".to_string()));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| CompanionError::Protocol("string is not UTF-8".to_string()))
+    }
+
+    fn opt<T>(&mut self, value: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(value(self)?)),
+            tag => Err(CompanionError::Protocol(format!("bad option tag {}", tag))),
+        }
+    }
+
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let count = self.u32()?;
+        // Don't trust the count for the allocation, every item takes at least a byte
+        let mut items = Vec::with_capacity((count as usize).min(self.0.len()));
+        for _ in 0..count {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn profile(&mut self) -> Result<Profile> {
+        let path_rule = |r: &mut Self| -> Result<PathRule> {
+            let (kind, pattern) = (path_match::from_tag(r.u8()?)?, r.str()?);
+            Ok(PathRule { kind, pattern, errno: r.u32()? as i32, child_errno: r.u32()? as i32 })
+        };
+        let pattern = self.str()?;
+        let hidden_paths = self.list(path_rule)?;
+        let allowed_paths = self.list(path_rule)?;
+        let hidden_maps = self.list(Self::str)?;
+        let hidden_mounts = self.list(|r| Ok(MountRule { field: mount_field::from_tag(r.u8()?)?, pattern: r.str()? }))?;
+        let props = self.list(|r| {
+            let (kind, pattern) = (prop_match::from_tag(r.u8()?)?, r.str()?);
+            let action = match r.u8()? {
+                0 => PropAction::Spoof(r.str()?),
+                1 => PropAction::Hide,
+                2 => PropAction::Passthrough,
+                tag => return Err(CompanionError::Protocol(format!("unknown prop action {}", tag))),
+            };
+            Ok(PropRule { kind, pattern, action })
+        })?;
+        let build_fields = self.list(|r| Ok(BuildField { name: r.str()?, value: r.str()? }))?;
+        let cleared_runtime_flags = self.list(|r| Ok(RuntimeFlags::from_raw(r.u32()? as i32)))?;
+        let blocked_packages = self.list(Self::str)?;
+        let log = self.opt(|r| log_backend::from_tag(r.u8()?))?;
+        Ok(Profile {
+            pattern,
+            hidden_paths,
+            allowed_paths,
+            hidden_maps,
+            hidden_mounts,
+            props,
+            build_fields,
+            cleared_runtime_flags,
+            blocked_packages,
+            log,
+        })
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.0.is_empty() {
+            true => Ok(()),
+            false => Err(CompanionError::Protocol("trailing bytes".to_string())),
+        }
+    }
+}
+
+/// A connected, handshaken socket that exchanges length-prefixed frames.
+struct Channel {
+    stream: UnixStream,
+}
+
+impl Channel {
+    fn new(fd: OwnedFd, timeout: Duration) -> Result<Channel> {
+        let stream = UnixStream::from(fd);
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Channel { stream })
+    }
+
+    fn send_hello(&mut self, version: u32) -> Result<()> {
+        let mut hello = MAGIC.to_vec();
+        hello.extend_from_slice(&version.to_le_bytes());
+        Ok(self.stream.write_all(&hello)?)
+    }
+
+    fn recv_hello(&mut self) -> Result<u32> {
+        let mut hello = [0; 8];
+        self.stream.read_exact(&mut hello)?;
+        if hello[..4] != MAGIC {
+            return Err(CompanionError::Protocol("not a GeoInk peer".to_string()));
+        }
+        Ok(u32::from_le_bytes(hello[4..].try_into().unwrap()))
+    }
+
+    /// Send a frame, with `fds` attached to its first bytes.
+    fn send(&mut self, body: &[u8], fds: &[BorrowedFd]) -> Result<()> {
+        let mut frame = Vec::with_capacity(4 + body.len());
+        frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        frame.extend_from_slice(body);
+        let sent = send_fds(self.stream.as_fd(), &frame, fds)?;
+        Ok(self.stream.write_all(&frame[sent..])?)
+    }
+
+    /// Read the next frame and the fds sent with it, or `None` if the other side closed the
+    /// connection between frames.
+    fn recv(&mut self) -> Result<Option<(Vec<u8>, Vec<OwnedFd>)>> {
+        let mut len = [0; 4];
+        let (received, fds) = recv_fds(self.stream.as_fd(), &mut len)?;
+        if received == 0 {
+            return Ok(None);
+        }
+        self.stream.read_exact(&mut len[received..])?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(CompanionError::Protocol(format!("frame of {} bytes", len)));
+        }
+        let mut body = vec![0; len];
+        self.stream.read_exact(&mut body)?;
+        Ok(Some((body, fds)))
+    }
+}
+
+/// The module side of a companion connection.
+///
+/// ```ignore
+/// let mut client = Client::connect(&api)?;
+/// client.request(&Request::Ping)?;
+/// ```
+pub struct Client {
+    channel: Channel,
+}
+
+impl Client {
+    /// Connect to the companion and check that it speaks our protocol.
+    ///
+    /// This uses [ZygiskApi::connect_companion], so it only works in `pre[XXX]Specialize`.
+    pub fn connect(api: &ZygiskApi) -> Result<Client> {
+        let fd = api.connect_companion();
+        if fd < 0 {
+            return Err(CompanionError::Unavailable);
+        }
+        Client::handshake(unsafe { OwnedFd::from_raw_fd(fd) }, CLIENT_TIMEOUT)
+    }
+
+    /// Handshake over an already connected socket.
+    pub fn handshake(fd: OwnedFd, timeout: Duration) -> Result<Client> {
+        let mut channel = Channel::new(fd, timeout)?;
+        channel.send_hello(PROTOCOL_VERSION)?;
+        let theirs = channel.recv_hello()?;
+        if theirs != PROTOCOL_VERSION {
+            return Err(CompanionError::VersionMismatch { ours: PROTOCOL_VERSION, theirs });
+        }
+        Ok(Client { channel })
+    }
+
+    /// Send a request and wait for its response. An error response is returned as
+    /// [CompanionError::Remote]. Any fds sent with the response are closed, see
+    /// [request_with_fds](Client::request_with_fds).
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        self.request_with_fds(request).map(|reply| reply.response)
+    }
+
+    /// Send a request and wait for its response, along with the fds the companion sent.
+    pub fn request_with_fds(&mut self, request: &Request) -> Result<Reply> {
+        let mut w = Writer::default();
+        request.encode(&mut w);
+        self.channel.send(&w.0, &[])?;
+
+        let (body, fds) = self.channel.recv()?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let mut r = Reader(&body);
+        let response = Response::decode(&mut r)?;
+        r.finish()?;
+        match response {
+            Response::Error(msg) => Err(CompanionError::Remote(msg)),
+            response => Ok(Reply::with_fds(response, fds)),
+        }
+    }
+}
+
+/// The companion side of a connection.
+pub struct Server {
+    channel: Channel,
+}
+
+impl Server {
+    /// Handshake with a module instance. `client` is the socket `zygisk_companion!` passes to
+    /// the handler; it is duplicated, as the runtime closes it once the handler returns.
+    ///
+    /// A client with another protocol version is told ours, then refused.
+    pub fn handshake(client: BorrowedFd, timeout: Duration) -> Result<Server> {
+        let mut channel = Channel::new(client.try_clone_to_owned()?, timeout)?;
+        let theirs = channel.recv_hello()?;
+        channel.send_hello(PROTOCOL_VERSION)?;
+        if theirs != PROTOCOL_VERSION {
+            return Err(CompanionError::VersionMismatch { ours: PROTOCOL_VERSION, theirs });
+        }
+        Ok(Server { channel })
+    }
+
+    /// Answer requests with `handler` until the client hangs up. The fds of each [Reply] are
+    /// sent along with its response, then closed on this side.
+    ///
+    /// A request that can't be decoded gets a [Response::Error] and ends the connection, as the
+    /// stream can't be trusted after it.
+    pub fn serve(&mut self, mut handler: impl FnMut(Request) -> Reply) -> Result<()> {
+        // Requests never carry fds, so whatever came along is dropped
+        while let Some((body, _)) = self.channel.recv()? {
+            let mut r = Reader(&body);
+            let request = Request::decode(&mut r).and_then(|request| r.finish().map(|()| request));
+            let (reply, result) = match request {
+                Ok(request) => (handler(request), Ok(())),
+                Err(e) => (Reply::from(Response::Error(e.to_string())), Err(e)),
+            };
+
+            let mut w = Writer::default();
+            reply.response.encode(&mut w);
+            let fds: Vec<BorrowedFd> = reply.fds.iter().map(|fd| fd.as_fd()).collect();
+            self.channel.send(&w.0, &fds)?;
+            result?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::thread;
+
+    use super::*;
+
+    fn pair() -> (OwnedFd, OwnedFd) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (a.into(), b.into())
+    }
+
+    fn serve(fd: OwnedFd, handler: fn(Request) -> Response) -> thread::JoinHandle<Result<()>> {
+        thread::spawn(move || {
+            let mut server = Server::handshake(fd.as_fd(), SERVER_TIMEOUT)?;
+            server.serve(|request| handler(request).into())
+        })
+    }
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    fn transfer(mut from: impl Write, mut to: impl Read) -> String {
+        from.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        to.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn requests_get_responses() {
+        let (client, server) = pair();
+        let server = serve(server, |request| match request {
+            Request::Ping => Response::Pong,
+            Request::GetProfile { process } if process == "com.bank" => Response::Profile(Some(Profile::new("x"))),
+            Request::GetProfile { .. } => Response::Profile(None),
+            Request::Events { .. } | Request::Log { .. } => Response::Done,
+        });
+
+        let mut client = Client::handshake(client, CLIENT_TIMEOUT).unwrap();
+        assert_eq!(client.request(&Request::Ping).unwrap(), Response::Pong);
+        let get = |process: &str| Request::GetProfile { process: process.to_string() };
+        assert_eq!(client.request(&get("com.bank")).unwrap(), Response::Profile(Some(Profile::new("x"))));
+        assert_eq!(client.request(&get("com.other")).unwrap(), Response::Profile(None));
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn profiles_survive_encoding() {
+        let text = "block = com.a\n[com.bank]\nhide = glob:/data/** errno=EACCES child_errno=ENOTDIR\n\
+                    allow = exact:/data/ok\nmaps_hide = zygisk\nmount_hide = root:/adb/**\npreset = verified-boot\n\
+                    prop = prefix:ro.lineage. hide\nbuild = TAGS release-keys\nclear_runtime_flag = DEBUGGABLE\n\
+                    log = file\n";
+        let profile = crate::config::Config::parse(text).unwrap().profile_for("com.bank").unwrap();
+        for response in [Response::Profile(Some(profile)), Response::Profile(None)] {
+            let mut w = Writer::default();
+            response.encode(&mut w);
+            let mut r = Reader(&w.0);
+            assert_eq!(Response::decode(&mut r).unwrap(), response);
+            r.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn events_survive_encoding() {
+        let event = |hook, action, subject: &str| Event { time: 1700000000, hook, action, subject: subject.into() };
+        let request = Request::Events {
+            pid: 4242,
+            process: "com.bank:remote".to_string(),
+            dropped: 7,
+            events: vec![
+                event("openat".into(), Action::Hide, "/system/addon.d"),
+                event("__system_property_get".into(), Action::Spoof, "ro.build.tags"),
+            ],
+        };
+        let lines = vec![LogLine { time: 1700000001, text: "INFO Hiding prop: ro.build.tags".to_string() }];
+        let log = Request::Log { pid: 4242, process: "com.bank:remote".to_string(), lines };
+        for request in [request, log] {
+            let mut w = Writer::default();
+            request.encode(&mut w);
+            let mut r = Reader(&w.0);
+            assert_eq!(Request::decode(&mut r).unwrap(), request);
+            r.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn error_responses_are_errors() {
+        let (client, server) = pair();
+        let server = serve(server, |_| Response::Error("no".to_string()));
+
+        let mut client = Client::handshake(client, CLIENT_TIMEOUT).unwrap();
+        assert!(matches!(client.request(&Request::Ping), Err(CompanionError::Remote(msg)) if msg == "no"));
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let (client, server) = pair();
+        let server = serve(server, |_| Response::Pong);
+
+        let mut channel = Channel::new(client, CLIENT_TIMEOUT).unwrap();
+        channel.send_hello(PROTOCOL_VERSION + 1).unwrap();
+        assert_eq!(channel.recv_hello().unwrap(), PROTOCOL_VERSION);
+        match server.join().unwrap() {
+            Err(CompanionError::VersionMismatch { theirs, .. }) => assert_eq!(theirs, PROTOCOL_VERSION + 1),
+            result => panic!("{:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn silent_companions_time_out() {
+        let (client, _server) = pair();
+        let result = Client::handshake(client, Duration::from_millis(50));
+        assert!(matches!(result, Err(CompanionError::Timeout)));
+    }
+
+    #[test]
+    fn garbage_is_refused() {
+        let (client, server) = pair();
+        let server = serve(server, |_| Response::Pong);
+
+        let mut channel = Channel::new(client, CLIENT_TIMEOUT).unwrap();
+        channel.send_hello(PROTOCOL_VERSION).unwrap();
+        channel.recv_hello().unwrap();
+        channel.send(&[0xff], &[]).unwrap();
+        let (reply, _) = channel.recv().unwrap().unwrap();
+        assert!(matches!(Response::decode(&mut Reader(&reply)), Ok(Response::Error(_))));
+        assert!(matches!(server.join().unwrap(), Err(CompanionError::Protocol(_))));
+
+        // Oversized frames are refused before anything is allocated
+        let (a, b) = pair();
+        UnixStream::from(a).write_all(&u32::MAX.to_le_bytes()).unwrap();
+        let mut channel = Channel::new(b, CLIENT_TIMEOUT).unwrap();
+        assert!(matches!(channel.recv(), Err(CompanionError::Protocol(_))));
+    }
+
+    #[test]
+    fn fds_pass_through_a_socketpair() {
+        let (a, b) = pair();
+        let (read, write) = pipe();
+        assert_eq!(send_fds(a.as_fd(), b"x", &[read.as_fd(), write.as_fd()]).unwrap(), 1);
+        assert_eq!(send_fds(a.as_fd(), b"y", &[]).unwrap(), 1);
+
+        let mut buf = [0; 1];
+        let (received, fds) = recv_fds(b.as_fd(), &mut buf).unwrap();
+        assert_eq!((received, &buf), (1, b"x"));
+        assert_eq!(fds.len(), 2);
+        let cloexec = unsafe { libc::fcntl(fds[0].as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(cloexec & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+
+        // Both ends are the same pipe as the originals
+        let mut fds = fds.into_iter().map(File::from);
+        let (their_read, their_write) = (fds.next().unwrap(), fds.next().unwrap());
+        assert_eq!(transfer(&write, their_read), "hello");
+        assert_eq!(transfer(their_write, &read), "hello");
+
+        let (received, fds) = recv_fds(b.as_fd(), &mut buf).unwrap();
+        assert_eq!((received, &buf, fds.len()), (1, b"y", 0));
+
+        assert!(send_fds(a.as_fd(), b"", &[read.as_fd()]).is_err());
+        drop(a);
+        assert_eq!(recv_fds(b.as_fd(), &mut buf).unwrap().0, 0);
+    }
+
+    #[test]
+    fn replies_carry_fds() {
+        let (client, server) = pair();
+        let (read, write) = pipe();
+        let server = thread::spawn(move || {
+            let mut server = Server::handshake(server.as_fd(), SERVER_TIMEOUT)?;
+            let mut read = Some(OwnedFd::from(read));
+            server.serve(|_| Reply::with_fds(Response::Pong, read.take().into_iter().collect()))
+        });
+
+        let mut client = Client::handshake(client, CLIENT_TIMEOUT).unwrap();
+        let reply = client.request_with_fds(&Request::Ping).unwrap();
+        assert_eq!(reply.response, Response::Pong);
+        let read = File::from(reply.fds.into_iter().next().unwrap());
+        assert_eq!(transfer(write, read), "hello");
+
+        // Fds of a plain request are closed rather than leaked
+        assert_eq!(client.request(&Request::Ping).unwrap(), Response::Pong);
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::OwnedFd;
+use std::sync::OnceLock;
+
+use regex_lite::Regex;
+
+use crate::{
+    build_fields::BuildField,
+    companion::{Client, Request, Response},
+    fd::ModuleFd,
+    logging::LogBackend,
+    maps::MapsFilter,
+    mounts::{MountFilter, MountRule},
+    paths::{PathRule, PathRules},
+    profile::Profile,
+    props::{PropAction, PropMatch, PropRule, PropRules},
+    RuntimeFlags, ZygiskApi,
+};
+
+/// Name of the config file, relative to the module root directory.
+pub const CONFIG_FILE: &CStr = c"geoink.conf";
+
+/// The config shipped with the module, also used whenever the installed one is unusable.
+pub const DEFAULT_CONFIG: &str = include_str!("../../../geoink_core/magisk_module/geoink.conf");
+
+static ACTIVE: OnceLock<ActiveProfile> = OnceLock::new();
+
+/// The rule set GeoInk-Core runs with.
+///
+/// The config file is a plain line format: one `key = value` pair per line, with `#` starting a
+/// comment at the start of a line or after whitespace. Keys may be repeated to build up lists.
+/// Rules before the first `[pattern]` header apply to every profile; rules after it only to
+/// processes matching that pattern (see [Profile] for how patterns are matched).
+///
+/// ```text
+/// # Packages no target app may reach
+/// deny = com.sukisu.ultra
+///
+/// [com.example.bank]
+/// hide = /system/addon.d
+/// hide = name:magisk
+/// maps_hide = libgeoink_core\.so
+/// mount_hide = source:magisk
+/// allow = exact:/system/addon.d/50-keep.sh
+/// preset = verified-boot
+/// build = TAGS release-keys
+/// clear_runtime_flag = DEBUGGABLE
+/// log = companion
+///
+/// [com.example.game:anticheat]
+/// prop = prefix:ro.lineage. hide
+/// ```
+///
+/// A global `target = <pattern>` line declares a profile that only gets the common rules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Rules shared by every profile.
+    pub common: Profile,
+    pub profiles: Vec<Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::parse(DEFAULT_CONFIG).expect("built-in config must parse")
+    }
+}
+
+/// A config file that could not be parsed.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Config {
+    /// Parse a config file. An empty file yields an empty rule set, not the defaults.
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config { common: Profile::default(), profiles: Vec::new() };
+        let mut in_section = false;
+
+        for (idx, raw) in text.lines().enumerate() {
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| ConfigError { line: idx + 1, msg: msg.to_string() };
+
+            if let Some(header) = line.strip_prefix('[') {
+                let pattern = header.strip_suffix(']').ok_or_else(|| err("unterminated `[`"))?.trim();
+                if pattern.is_empty() {
+                    return Err(err("empty profile pattern"));
+                }
+                config.profiles.push(Profile::new(pattern));
+                in_section = true;
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| err("expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+            if value.is_empty() {
+                return Err(err("empty value"));
+            }
+
+            let profile = match config.profiles.last_mut() {
+                Some(profile) if in_section => profile,
+                _ => &mut config.common,
+            };
+            match key {
+                "target" if !in_section => config.profiles.push(Profile::new(value)),
+                "target" => return Err(err("`target` is only allowed before the first profile")),
+                "hide" => profile.hidden_paths.push(PathRule::parse(value).map_err(|e| err(&e))?),
+                "allow" => profile.allowed_paths.push(PathRule::parse(value).map_err(|e| err(&e))?),
+                "maps_hide" => {
+                    Regex::new(value).map_err(|e| err(&format!("bad regex `{}`: {}", value, e)))?;
+                    profile.hidden_maps.push(value.to_string());
+                }
+                "mount_hide" => profile.hidden_mounts.push(MountRule::parse(value).map_err(|e| err(&e))?),
+                "prop" => profile.props.push(PropRule::parse(value).map_err(|e| err(&e))?),
+                "preset" => {
+                    let preset = PropRule::preset(value).ok_or_else(|| err(&format!("unknown preset `{}`", value)))?;
+                    profile.props.extend(preset);
+                }
+                // Shorthands for the two most common property rules
+                "spoof_prop" => {
+                    let (name, fake) = value
+                        .split_once('=')
+                        .ok_or_else(|| err("expected `spoof_prop = <name> = <value>`"))?;
+                    profile.props.push(PropRule::spoof(name.trim(), fake.trim()).map_err(|e| err(&e))?);
+                }
+                "hide_prop" => profile.props.push(PropRule {
+                    kind: PropMatch::Regex,
+                    pattern: regex_lite::escape(value),
+                    action: PropAction::Hide,
+                }),
+                "build" => profile.build_fields.push(BuildField::parse(value).map_err(|e| err(&e))?),
+                "clear_runtime_flag" => {
+                    let flags = RuntimeFlags::from_name(value)
+                        .ok_or_else(|| err(&format!("unknown runtime flag `{}`", value)))?;
+                    profile.cleared_runtime_flags.push(flags);
+                }
+                "block" | "deny" => profile.blocked_packages.push(value.to_string()),
+                "log" => profile.log = Some(LogBackend::parse(value).map_err(|e| err(&e))?),
+                _ => return Err(err(&format!("unknown key `{}`", key))),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Load the config from the module directory, falling back to the built-in defaults when
+    /// the file is missing or malformed.
+    ///
+    /// This uses [ZygiskApi::get_module_dir], so it only works in `pre[XXX]Specialize`.
+    pub fn load(api: &ZygiskApi) -> Config {
+        match read_module_file(api, CONFIG_FILE) {
+            Some(text) => Config::parse(&text).unwrap_or_else(|e| {
+                error!("Malformed {:?} ({}), using defaults", CONFIG_FILE, e);
+                Config::default()
+            }),
+            None => Config::default(),
+        }
+    }
+
+    /// Get the profile for a process from the companion, which keeps the config parsed and up
+    /// to date for every process and resolves the profile, so the process doesn't parse
+    /// anything. If the companion can't be reached, the config is read from the module directory
+    /// instead.
+    ///
+    /// This only works in `pre[XXX]Specialize`.
+    pub fn fetch_profile(api: &ZygiskApi, process_name: &str) -> Option<Profile> {
+        let request = Request::GetProfile { process: process_name.to_string() };
+        let response = Client::connect(api).and_then(|mut client| client.request(&request));
+        match response {
+            Ok(Response::Profile(profile)) => return profile,
+            Ok(response) => error!("Unexpected companion response {:?}", response),
+            Err(e) => warn!("Unable to get the config from the companion: {}", e),
+        }
+        Config::load(api).profile_for(process_name)
+    }
+
+    /// Build the effective profile for a process: the common rules plus the rules of every
+    /// profile matching it, in file order. Returns `None` if no profile matches.
+    pub fn profile_for(&self, process_name: &str) -> Option<Profile> {
+        let mut matched = self.profiles.iter().filter(|p| p.matches(process_name)).peekable();
+        matched.peek()?;
+
+        let mut effective = Profile::new(process_name);
+        effective.merge(&self.common);
+        for profile in matched {
+            effective.merge(profile);
+        }
+        Some(effective)
+    }
+}
+
+/// Read a file relative to the module root directory.
+fn read_module_file(api: &ZygiskApi, name: &CStr) -> Option<String> {
+    let fd = match ModuleFd::open_module_file(api, name, libc::O_RDONLY) {
+        Ok(fd) => fd,
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return None,
+        Err(e) => {
+            error!("Unable to open {:?}: {}", name, e);
+            return None;
+        }
+    };
+
+    let mut text = String::new();
+    let mut file = File::from(OwnedFd::from(fd));
+    match file.read_to_string(&mut text) {
+        Ok(_) => Some(text),
+        Err(e) => {
+            error!("Unable to read {:?}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Cut the comment off a line. A `#` inside a value, as in `spoof_prop = ro.x = a#b` or a
+/// regex, is kept.
+fn strip_comment(line: &str) -> &str {
+    let mut prev = None;
+    for (i, c) in line.char_indices() {
+        if c == '#' && prev.is_none_or(char::is_whitespace) {
+            return &line[..i];
+        }
+        prev = Some(c);
+    }
+    line
+}
+
+/// A profile compiled into the lookup structures the hooks query.
+#[derive(Debug, Default)]
+pub struct ActiveProfile {
+    pub profile: Profile,
+    pub paths: PathRules,
+    pub maps: MapsFilter,
+    pub mounts: MountFilter,
+    pub props: PropRules,
+}
+
+impl ActiveProfile {
+    fn compile(profile: Profile) -> ActiveProfile {
+        let paths = PathRules::new(&profile.hidden_paths, &profile.allowed_paths);
+        let maps = MapsFilter::new(&profile.hidden_maps);
+        let mounts = MountFilter::new(&profile.hidden_mounts);
+        let props = PropRules::new(&profile.props);
+        ActiveProfile { profile, paths, maps, mounts, props }
+    }
+}
+
+/// Make `profile` the rule set for the rest of this process's lifetime.
+///
+/// Only the first call has any effect, as the hooks read the active profile without locking.
+pub fn install(profile: Profile) -> &'static ActiveProfile {
+    ACTIVE.get_or_init(|| ActiveProfile::compile(profile))
+}
+
+/// The profile installed for this process, or an empty one that hides nothing.
+pub fn active() -> &'static ActiveProfile {
+    ACTIVE.get_or_init(ActiveProfile::default)
+}
+
+/// The config of tests that go through the hooks. Only one profile can ever be installed, so
+/// they all share it.
+#[cfg(test)]
+pub const TEST_CONFIG: &str = "[test]
+hide = /system/addon.d child_errno=ENOTDIR
+maps_hide = geoink-maps-test
+spoof_prop = persist.vendor.geoink.test.long.name = spoofed
+block = com.sukisu.ultra
+";
+
+/// Install the profile of [TEST_CONFIG].
+#[cfg(test)]
+pub fn install_for_tests() -> &'static ActiveProfile {
+    let profile = Config::parse(TEST_CONFIG).unwrap().profile_for("test").unwrap();
+    let active = install(profile);
+    assert_eq!(active.profile.pattern, "test", "a test used the hooks before installing the test profile");
+    active
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_config_parses() {
+        let config = Config::parse(DEFAULT_CONFIG).unwrap();
+        let profile = config.profile_for("com.rem01gaming.disclosure:remote").unwrap();
+        assert!(profile.blocks_package("com.sukisu.ultra"));
+        assert!(!profile.props.is_empty());
+        assert!(config.profile_for("com.android.settings").is_none());
+    }
+
+    #[test]
+    fn profiles_merge_common_rules() {
+        let config = Config::parse(
+            "block = com.a\n[com.bank]\nhide = /x\n[com.bank:svc]\nhide = /y\n[com.game.*]\nhide = /z\n",
+        )
+        .unwrap();
+        let svc = config.profile_for("com.bank:svc").unwrap();
+        assert_eq!(svc.hidden_paths.len(), 2);
+        assert!(svc.blocks_package("com.a"));
+        assert_eq!(config.profile_for("com.bank").unwrap().hidden_paths.len(), 1);
+        assert_eq!(config.profile_for("com.game.x:anticheat").unwrap().hidden_paths.len(), 1);
+        assert!(config.profile_for("com.banking").is_none());
+    }
+
+    #[test]
+    fn comments_need_a_leading_space() {
+        let config = Config::parse("# header\nmaps_hide = lib#1\\.so # trailing\n\tmaps_hide = x#\n").unwrap();
+        assert_eq!(config.common.hidden_maps, ["lib#1\\.so", "x#"]);
+        assert_eq!(strip_comment("#hide = /x"), "");
+        assert_eq!(strip_comment("hide = /x\t# why"), "hide = /x\t");
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        let err = Config::parse("[com.bank]\nhide = relative/path\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(Config::parse("bogus = 1").is_err());
+        assert!(Config::parse("[com.bank]\ntarget = com.x").is_err());
+        assert!(Config::parse("preset = nope").is_err());
+        assert!(Config::parse("maps_hide = lib(zygisk").is_err());
+        assert!(Config::parse("build = TAGS").is_err());
+        assert!(Config::parse("build = Build.TAGS release-keys").is_err());
+        assert!(Config::parse("clear_runtime_flag = JDWP").is_err());
+        assert!(Config::parse("log = stderr").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{BorrowedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::{
+    companion::{Request, Response, Server, SERVER_TIMEOUT},
+    config::{Config, CONFIG_FILE, DEFAULT_CONFIG},
+    events::Event,
+    logging::{self, LogBackend, LogLine, LOG_FILE},
+    profile::Profile,
+};
+
+/// The module id, `magiskModuleId` in `module.gradle`, which the build passes on. Builds outside
+/// of Gradle get the default one.
+pub const MODULE_ID: &str = match option_env!("GEOINK_MODULE_ID") {
+    Some(id) => id,
+    None => "geoink-core",
+};
+
+/// Where the module is installed. The companion runs as root outside of any app sandbox, so it
+/// can read the module directly.
+pub fn module_dir() -> PathBuf {
+    Path::new("/data/adb/modules").join(MODULE_ID)
+}
+
+/// Name of the event log, relative to the module directory.
+pub const EVENT_LOG: &str = "events.log";
+
+/// Enough of a file's metadata to tell that it was changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileStamp {
+    ino: u64,
+    len: u64,
+    mtime: (i64, i64),
+}
+
+impl FileStamp {
+    fn of(meta: &fs::Metadata) -> FileStamp {
+        FileStamp { ino: meta.ino(), len: meta.len(), mtime: (meta.mtime(), meta.mtime_nsec()) }
+    }
+}
+
+/// A config that parsed.
+struct Loaded {
+    stamp: Option<FileStamp>,
+    config: Config,
+}
+
+/// The config file as last read by the companion.
+///
+/// The file is stat'ed on every request and read again whenever it changed, so edits apply to
+/// the next app that starts. An edit that doesn't parse is logged and ignored, keeping the last
+/// config that did, or the built-in one if none ever did.
+pub struct ConfigCache {
+    path: PathBuf,
+    loaded: Mutex<Option<Loaded>>,
+}
+
+impl ConfigCache {
+    pub fn new(path: impl Into<PathBuf>) -> ConfigCache {
+        ConfigCache { path: path.into(), loaded: Mutex::new(None) }
+    }
+
+    /// The effective profile of `process`, or `None` if no profile covers it.
+    pub fn profile_for(&self, process: &str) -> Option<Profile> {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        self.refresh(&mut loaded).config.profile_for(process)
+    }
+
+    fn refresh<'a>(&self, loaded: &'a mut Option<Loaded>) -> &'a Loaded {
+        let stamp = fs::metadata(&self.path).ok().map(|meta| FileStamp::of(&meta));
+        if loaded.as_ref().is_some_and(|l| l.stamp == stamp) {
+            return loaded.as_ref().unwrap();
+        }
+        if stamp.is_none() && loaded.is_none() {
+            warn!("No config at {:?}, using the built-in one", self.path);
+        }
+
+        let text = match stamp {
+            Some(_) => fs::read_to_string(&self.path).map_err(|e| error!("Unable to read {:?}: {}", self.path, e)).ok(),
+            // A deleted config means the built-in one, as it does when an app reads it
+            None => Some(DEFAULT_CONFIG.to_string()),
+        };
+        let parsed = text.and_then(|text| match Config::parse(&text) {
+            Ok(config) => Some(Loaded { stamp, config }),
+            Err(e) => {
+                error!("Malformed {:?} ({}), keeping the previous config", self.path, e);
+                None
+            }
+        });
+        match (parsed, loaded.take()) {
+            (Some(new), _) => {
+                info!("Loaded {:?}", self.path);
+                *loaded = Some(new);
+            }
+            // Remember the stamp, so the broken file isn't parsed again on every request
+            (None, Some(previous)) => *loaded = Some(Loaded { stamp, ..previous }),
+            (None, None) => *loaded = Some(Loaded { stamp, config: Config::default() }),
+        }
+        loaded.as_ref().unwrap()
+    }
+}
+
+/// A log shared by every target process, one line each: `<time> <pid> <process> <text>`.
+///
+/// Once the file grows past `max_len`, it is moved to `<name>.1` (and that to `<name>.2`, ...)
+/// before the next batch is written, keeping `keep` old files.
+pub struct LogFile {
+    path: PathBuf,
+    max_len: u64,
+    keep: usize,
+    lock: Mutex<()>,
+}
+
+impl LogFile {
+    pub fn new(path: impl Into<PathBuf>, max_len: u64, keep: usize) -> LogFile {
+        LogFile { path: path.into(), max_len, keep, lock: Mutex::new(()) }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            if Path::new(&self.rotated(n)).exists() {
+                fs::rename(self.rotated(n), self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    /// Append a batch of lines from one process.
+    pub fn append(&self, pid: i32, process: &str, lines: &[LogLine]) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        if fs::metadata(&self.path).is_ok_and(|meta| meta.len() >= self.max_len) {
+            self.rotate()?;
+        }
+
+        let mut text = String::new();
+        for line in lines {
+            // Keep every line on one line, whatever the app passed in
+            let escaped = line.text.replace('\n', "\\n");
+            text.push_str(&format!("{} {} {} {}\n", line.time, pid, process, escaped));
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(text.as_bytes())
+    }
+
+    /// Append a batch of events from one process, as `<hook> <action> <subject>`, after a
+    /// `- dropped <n>` line if some were lost.
+    pub fn append_events(&self, pid: i32, process: &str, dropped: u64, events: &[Event]) -> io::Result<()> {
+        let mut lines = Vec::with_capacity(events.len() + 1);
+        if dropped > 0 {
+            let time = events.first().map_or(0, |e| e.time);
+            lines.push(LogLine { time, text: format!("- dropped {}", dropped) });
+        }
+        lines.extend(events.iter().map(|e| LogLine {
+            time: e.time,
+            text: format!("{} {} {}", e.hook, e.action.name(), e.subject),
+        }));
+        self.append(pid, process, &lines)
+    }
+}
+
+/// The config cache of the companion process, shared by every connection.
+fn cache() -> &'static ConfigCache {
+    static CACHE: OnceLock<ConfigCache> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        let name = CONFIG_FILE.to_str().expect("config file name is UTF-8");
+        ConfigCache::new(module_dir().join(name))
+    })
+}
+
+/// The event log of the companion process, shared by every connection.
+fn event_log() -> &'static LogFile {
+    static LOG: OnceLock<LogFile> = OnceLock::new();
+    LOG.get_or_init(|| LogFile::new(module_dir().join(EVENT_LOG), 512 * 1024, 2))
+}
+
+/// The log the `companion` backend writes to, shared by every connection.
+fn module_log() -> &'static LogFile {
+    static LOG: OnceLock<LogFile> = OnceLock::new();
+    LOG.get_or_init(|| {
+        let name = LOG_FILE.to_str().expect("log file name is UTF-8");
+        LogFile::new(module_dir().join(name), 512 * 1024, 2)
+    })
+}
+
+/// Answer a request from a module instance.
+pub fn handle(request: Request) -> Response {
+    match request {
+        Request::Ping => Response::Pong,
+        Request::GetProfile { process } => Response::Profile(cache().profile_for(&process)),
+        Request::Events { pid, process, dropped, events } => {
+            match event_log().append_events(pid, &process, dropped, &events) {
+                Ok(()) => Response::Done,
+                Err(e) => Response::Error(format!("unable to write the event log: {}", e)),
+            }
+        }
+        Request::Log { pid, process, lines } => match module_log().append(pid, &process, &lines) {
+            Ok(()) => Response::Done,
+            Err(e) => Response::Error(format!("unable to write the log: {}", e)),
+        },
+    }
+}
+
+/// The companion request handler, run by the root daemon for each module instance that connects.
+pub fn serve(client: RawFd) {
+    // The companion is not an app, logcat is fine here
+    logging::init();
+    logging::set_backend(LogBackend::Logcat);
+    // The runtime keeps ownership of the socket, and closes it when this returns
+    let client = unsafe { BorrowedFd::borrow_raw(client) };
+    let result = Server::handshake(client, SERVER_TIMEOUT)
+        .and_then(|mut server| server.serve(|request| handle(request).into()));
+    if let Err(e) = result {
+        error!("Companion connection failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_changes_are_picked_up() {
+        let path = std::env::temp_dir().join(format!("geoink-daemon-{}.conf", std::process::id()));
+        let cache = ConfigCache::new(&path);
+
+        let hidden = |process: &str| cache.profile_for(process).map(|p| p.hidden_paths[0].pattern.clone());
+
+        // No file means the built-in config
+        assert!(cache.profile_for("com.rem01gaming.disclosure").is_some());
+        assert!(cache.profile_for("com.bank").is_none());
+
+        fs::write(&path, "[com.bank]\nhide = /x\n").unwrap();
+        assert_eq!(hidden("com.bank:remote").as_deref(), Some("/x"));
+        assert_eq!(cache.profile_for("com.bank").unwrap().pattern, "com.bank");
+        assert!(cache.profile_for("com.rem01gaming.disclosure").is_none());
+
+        // A broken edit keeps the last good config
+        fs::write(&path, "[com.bank]\nhide = relative\n").unwrap();
+        assert_eq!(hidden("com.bank").as_deref(), Some("/x"));
+
+        fs::write(&path, "[com.bank.app]\nhide = /y\n").unwrap();
+        assert!(cache.profile_for("com.bank").is_none());
+        assert_eq!(hidden("com.bank.app").as_deref(), Some("/y"));
+
+        fs::remove_file(&path).unwrap();
+        assert!(cache.profile_for("com.rem01gaming.disclosure").is_some());
+    }
+
+    #[test]
+    fn event_logs_rotate() {
+        let dir = std::env::temp_dir().join(format!("geoink-events-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = LogFile::new(dir.join("events.log"), 100, 2);
+        let event = |subject: &str| Event {
+            time: 1700000000,
+            hook: "openat".into(),
+            action: crate::events::Action::Hide,
+            subject: subject.to_string(),
+        };
+
+        log.append_events(42, "com.bank", 3, &[event("/system/addon.d"), event("a\nb")]).unwrap();
+        let text = fs::read_to_string(dir.join("events.log")).unwrap();
+        assert_eq!(
+            text,
+            "1700000000 42 com.bank - dropped 3\n\
+             1700000000 42 com.bank openat hide /system/addon.d\n\
+             1700000000 42 com.bank openat hide a\\nb\n"
+        );
+
+        // Each batch now starts a new file, and only two old ones are kept
+        for i in 0..4 {
+            log.append_events(42, "com.bank", 0, &[event(&format!("/{}/{}", i, "x".repeat(100)))]).unwrap();
+        }
+        let current = fs::read_to_string(dir.join("events.log")).unwrap();
+        assert!(current.contains("/3/"));
+        assert!(fs::read_to_string(dir.join("events.log.1")).unwrap().contains("/2/"));
+        assert!(fs::read_to_string(dir.join("events.log.2")).unwrap().contains("/1/"));
+        assert!(!dir.join("events.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use libc::c_int;
+
+/// The errno values a hiding rule may report, by their C names.
+const NAMES: &[(&str, c_int)] = &[
+    ("ENOENT", libc::ENOENT),
+    ("ENOTDIR", libc::ENOTDIR),
+    ("EACCES", libc::EACCES),
+    ("EPERM", libc::EPERM),
+    ("ELOOP", libc::ELOOP),
+];
+
+pub fn from_name(name: &str) -> Option<c_int> {
+    NAMES.iter().find(|(n, _)| *n == name).map(|(_, e)| *e)
+}
+
+pub fn name(errno: c_int) -> &'static str {
+    NAMES.iter().find(|(_, e)| *e == errno).map(|(n, _)| *n).unwrap_or("E?")
+}
+
+/// Set the calling thread's `errno`.
+///
+/// A hiding hook must always set `errno` when it fails a call, as the caller would otherwise see
+/// whatever was left over from an earlier call, which is a detection signal in itself.
+pub fn set(errno: c_int) {
+    #[cfg(target_os = "android")]
+    unsafe {
+        *libc::__errno() = errno;
+    }
+    #[cfg(not(target_os = "android"))]
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::ffi::CString;
+    use std::io::Error;
+
+    use super::*;
+    use crate::paths::{PathRule, PathRules};
+
+    fn last_errno() -> Option<c_int> {
+        Error::last_os_error().raw_os_error()
+    }
+
+    fn real_stat_errno(path: &str) -> Option<c_int> {
+        let path = CString::new(path).unwrap();
+        let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::stat(path.as_ptr(), &mut buf) }, -1);
+        last_errno()
+    }
+
+    #[test]
+    fn set_is_seen_by_the_caller() {
+        set(0);
+        set(libc::ENOENT);
+        assert_eq!(last_errno(), Some(libc::ENOENT));
+        set(libc::ENOTDIR);
+        assert_eq!(last_errno(), Some(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn hidden_paths_fail_like_the_kernel() {
+        let dir = std::env::temp_dir().join(format!("geoink-errno-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        // A hidden directory looks exactly like a missing one, children included
+        let rules = PathRules::new(&[PathRule::parse("/system/addon.d").unwrap()], &[]);
+        let missing = format!("{}/missing", dir);
+        for (hidden, real) in [
+            ("/system/addon.d", missing.clone()),
+            ("/system/addon.d/50-x.sh", format!("{}/50-x.sh", missing)),
+        ] {
+            set(rules.hidden_errno(hidden).unwrap());
+            assert_eq!(last_errno(), real_stat_errno(&real), "{}", hidden);
+        }
+
+        // A rule may instead pose as a regular file, so lookups below it fail with ENOTDIR
+        let rules = PathRules::new(
+            &[PathRule::parse("/system/addon.d errno=ENOENT child_errno=ENOTDIR").unwrap()],
+            &[],
+        );
+        set(rules.hidden_errno("/system/addon.d/50-x.sh").unwrap());
+        assert_eq!(last_errno(), real_stat_errno(&format!("{}/file/50-x.sh", dir)));
+        assert_eq!(last_errno(), Some(libc::ENOTDIR));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    companion::{Client, Request, CLIENT_TIMEOUT},
+    fd::ModuleFd,
+    logging, ZygiskApi,
+};
+
+/// How many events are kept in the process until they are flushed. Past that, the oldest are
+/// dropped, and only counted.
+pub const CAPACITY: usize = 512;
+
+/// Flush early once this many events are waiting.
+const FLUSH_THRESHOLD: usize = CAPACITY / 4;
+
+/// Flush at least this often, pinging the companion if there is nothing to send. This has to
+/// stay well below [SERVER_TIMEOUT](crate::SERVER_TIMEOUT), after which the companion gives up
+/// on an idle connection, and there is no reconnecting after specialization.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a hook did about something the app asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Reported as nonexistent.
+    Hide,
+    /// Answered with a fake value.
+    Spoof,
+    /// Answered with some of the real content left out.
+    Filter,
+    /// Refused.
+    Block,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [Action::Hide, Action::Spoof, Action::Filter, Action::Block];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Hide => "hide",
+            Action::Spoof => "spoof",
+            Action::Filter => "filter",
+            Action::Block => "block",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// Something a hook did in a target process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Seconds since the epoch.
+    pub time: u64,
+    /// The hooked function, e.g. `openat` or `startActivity`.
+    pub hook: Cow<'static, str>,
+    pub action: Action,
+    /// What the app asked about: a path, a property, a package, ...
+    pub subject: String,
+}
+
+/// The events recorded since the last flush.
+struct Ring {
+    events: VecDeque<Event>,
+    dropped: u64,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { events: VecDeque::new(), dropped: 0 });
+
+/// Held by tests that record events, as they share the ring.
+#[cfg(test)]
+pub static TEST_LOCK: Mutex<()> = Mutex::new(());
+static PENDING: Condvar = Condvar::new();
+
+/// Record an event. This never blocks on anything but other hooks recording theirs.
+pub fn record(hook: &'static str, action: Action, subject: impl Into<String>) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let event = Event { time, hook: Cow::Borrowed(hook), action, subject: subject.into() };
+
+    let mut ring = RING.lock().unwrap_or_else(|e| e.into_inner());
+    if ring.events.len() == CAPACITY {
+        ring.events.pop_front();
+        ring.dropped += 1;
+    }
+    ring.events.push_back(event);
+    if ring.events.len() == FLUSH_THRESHOLD {
+        PENDING.notify_one();
+    }
+}
+
+/// Take every recorded event, and the number of events dropped since the last call.
+pub fn drain() -> (Vec<Event>, u64) {
+    let mut ring = RING.lock().unwrap_or_else(|e| e.into_inner());
+    (ring.events.drain(..).collect(), std::mem::take(&mut ring.dropped))
+}
+
+/// The companion connection events are flushed to.
+struct Sink {
+    client: Client,
+    pid: i32,
+    process: String,
+}
+
+static SINK: OnceLock<Mutex<Option<Sink>>> = OnceLock::new();
+
+/// Open the connection events will be flushed to, and keep it open across specialization.
+///
+/// This only works in `pre[XXX]Specialize`, and only on runtimes that can exempt fds (API v4 and
+/// later). Otherwise events stay in the process, and the oldest are dropped once it is full.
+pub fn connect(api: &ZygiskApi, process: &str) {
+    let fd = api.connect_companion();
+    if fd < 0 {
+        return;
+    }
+    let mut fd = ModuleFd::new(unsafe { OwnedFd::from_raw_fd(fd) });
+    if !fd.exempt(api) {
+        return;
+    }
+    match Client::handshake(fd.into_owned(), CLIENT_TIMEOUT) {
+        Ok(client) => {
+            let sink = Sink { client, pid: std::process::id() as i32, process: process.to_string() };
+            let _ = SINK.set(Mutex::new(Some(sink)));
+        }
+        Err(e) => error!("Unable to open the event connection: {}", e),
+    }
+}
+
+/// Start the thread that flushes events to the companion, if [connect] succeeded.
+///
+/// This must only be called after specialization, so that the thread runs with the app's
+/// credentials rather than zygote's.
+pub fn start_flusher() {
+    if SINK.get().is_none() {
+        return;
+    }
+    let spawned = std::thread::Builder::new().name("geoink-events".to_string()).spawn(|| loop {
+        let ring = RING.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = PENDING.wait_timeout(ring, FLUSH_INTERVAL);
+        if !flush() {
+            break;
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Unable to start the event flusher: {}", e);
+    }
+}
+
+/// Send everything recorded to the companion, along with the lines of the `companion` log
+/// backend, or a ping to keep the connection open if there's nothing. Returns `false` once the
+/// connection is gone.
+pub fn flush() -> bool {
+    let Some(sink) = SINK.get() else { return false };
+    let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(Sink { client, pid, process }) = sink.as_mut() else { return false };
+
+    let mut requests = Vec::new();
+    let (events, dropped) = drain();
+    if !events.is_empty() || dropped > 0 {
+        requests.push(Request::Events { pid: *pid, process: process.clone(), dropped, events });
+    }
+    let lines = logging::drain();
+    if !lines.is_empty() {
+        requests.push(Request::Log { pid: *pid, process: process.clone(), lines });
+    }
+    if requests.is_empty() {
+        requests.push(Request::Ping);
+    }
+
+    for request in &requests {
+        if let Err(e) = client.request(request) {
+            *sink = None;
+            // With the `companion` backend this goes nowhere, as it should
+            error!("Unable to flush events, dropping them: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::companion::{Response, Server};
+
+    #[test]
+    fn the_ring_keeps_the_newest_events() {
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        drain();
+        for i in 0..CAPACITY + 3 {
+            record("openat", Action::Hide, format!("/data/{}", i));
+        }
+        let (events, dropped) = drain();
+        assert_eq!(events.len(), CAPACITY);
+        assert_eq!(dropped, 3);
+        assert_eq!(events[0].subject, "/data/3");
+        assert_eq!(events.last().unwrap().subject, format!("/data/{}", CAPACITY + 2));
+        assert_eq!(events[0].hook, "openat");
+
+        assert_eq!(drain(), (Vec::new(), 0));
+        assert_eq!(Action::from_name(Action::Filter.name()), Some(Action::Filter));
+    }
+
+    #[test]
+    fn idle_connections_stay_open() {
+        let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        drain();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut server = Server::handshake(server.as_fd(), Duration::from_millis(200))?;
+            let mut received = Vec::new();
+            server.serve(|request| {
+                if let Request::Events { events, .. } = request {
+                    received.extend(events);
+                }
+                Response::Done.into()
+            })?;
+            Ok::<_, crate::CompanionError>(received)
+        });
+        let client = Client::handshake(client.into(), CLIENT_TIMEOUT).unwrap();
+        let sink = Sink { client, pid: 42, process: "com.bank".to_string() };
+        assert!(SINK.set(Mutex::new(Some(sink))).is_ok());
+
+        // Idle for twice the server timeout
+        for _ in 0..4 {
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(flush());
+        }
+        record("openat", Action::Hide, "/system/addon.d");
+        assert!(flush());
+        assert!(SINK.get().unwrap().lock().unwrap().take().is_some());
+
+        let received = server.join().unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].subject, "/system/addon.d");
+    }
+}
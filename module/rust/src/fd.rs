@@ -0,0 +1,187 @@
+use std::collections::BTreeSet;
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+
+use crate::ZygiskApi;
+
+/// Every fd currently exempted through a [ModuleFd].
+static EXEMPTED: Mutex<BTreeSet<RawFd>> = Mutex::new(BTreeSet::new());
+
+/// A file descriptor owned by the module, which can be kept open across specialization.
+///
+/// Zygote closes every fd it doesn't know about when it specializes, so files the module opens
+/// in `pre[XXX]Specialize` (the only place where the module directory is accessible) are gone by
+/// the time the app runs, unless they are exempted with [ZygiskApi::exempt_fd]. This keeps track
+/// of which fds were, see [exempted_fds].
+///
+/// The fd is closed when this is dropped.
+#[derive(Debug)]
+pub struct ModuleFd {
+    fd: OwnedFd,
+    exempted: bool,
+}
+
+impl ModuleFd {
+    pub fn new(fd: OwnedFd) -> ModuleFd {
+        ModuleFd { fd, exempted: false }
+    }
+
+    /// Open a file relative to the module root directory.
+    ///
+    /// This uses [ZygiskApi::get_module_dir], so it only works in `pre[XXX]Specialize`.
+    pub fn open_module_file(api: &ZygiskApi, name: &CStr, flags: i32) -> io::Result<ModuleFd> {
+        let dir_fd = api.get_module_dir();
+        if dir_fd < 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "module directory is unavailable"));
+        }
+        let dir = unsafe { OwnedFd::from_raw_fd(dir_fd) };
+
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, 0o600) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ModuleFd::new(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Ask the runtime to keep this fd open across specialization, returning whether it will.
+    ///
+    /// This only works in `pre[XXX]Specialize`, and only on runtimes with API v4 or later.
+    pub fn exempt(&mut self, api: &ZygiskApi) -> bool {
+        if !self.exempted && api.exempt_fd(self.fd.as_raw_fd()) {
+            self.exempted = true;
+            EXEMPTED.lock().unwrap_or_else(|e| e.into_inner()).insert(self.fd.as_raw_fd());
+        }
+        self.exempted
+    }
+
+    pub fn is_exempted(&self) -> bool {
+        self.exempted
+    }
+
+    /// Take back ownership of the fd. It stays exempted if it was, but is no longer tracked.
+    pub fn into_owned(self) -> OwnedFd {
+        let this = std::mem::ManuallyDrop::new(self);
+        this.untrack();
+        unsafe { std::ptr::read(&this.fd) }
+    }
+
+    fn untrack(&self) {
+        if self.exempted {
+            EXEMPTED.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.fd.as_raw_fd());
+        }
+    }
+}
+
+impl Drop for ModuleFd {
+    fn drop(&mut self) {
+        self.untrack();
+    }
+}
+
+impl AsFd for ModuleFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for ModuleFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for ModuleFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_owned().into_raw_fd()
+    }
+}
+
+impl From<OwnedFd> for ModuleFd {
+    fn from(fd: OwnedFd) -> ModuleFd {
+        ModuleFd::new(fd)
+    }
+}
+
+impl From<ModuleFd> for OwnedFd {
+    fn from(fd: ModuleFd) -> OwnedFd {
+        fd.into_owned()
+    }
+}
+
+/// The fds that are currently open and exempted through a [ModuleFd].
+pub fn exempted_fds() -> Vec<RawFd> {
+    EXEMPTED.lock().unwrap_or_else(|e| e.into_inner()).iter().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_int;
+
+    use super::*;
+    use crate::binding::{RawApiTable, RawApiTableV2, RawApiTableV4};
+
+    extern "C" fn exempt_fd(_: c_int) -> bool {
+        true
+    }
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn base() -> RawApiTable {
+        RawApiTable { this: std::ptr::null(), register_module: None }
+    }
+
+    #[test]
+    fn exempted_fds_are_tracked_while_open() {
+        let table = RawApiTableV4 {
+            base: base(),
+            hook_jni_native_methods: None,
+            plt_hook_register: None,
+            exempt_fd: Some(exempt_fd),
+            plt_hook_commit: None,
+            connect_companion: None,
+            set_option: None,
+            get_module_dir: None,
+            get_flags: None,
+        };
+        let api = ZygiskApi::from_raw(&table.base, 4);
+
+        let (read, write) = pipe();
+        let (mut read, write) = (ModuleFd::new(read), ModuleFd::from(write));
+        let read_fd = read.as_raw_fd();
+        assert!(read.exempt(&api));
+        assert!(read.is_exempted() && !write.is_exempted());
+        assert!(exempted_fds().contains(&read_fd));
+        assert!(!exempted_fds().contains(&write.as_raw_fd()));
+
+        drop(read);
+        assert!(!exempted_fds().contains(&read_fd));
+    }
+
+    #[test]
+    fn nothing_is_exempted_before_api_v4() {
+        let table = RawApiTableV2 {
+            base: base(),
+            hook_jni_native_methods: None,
+            plt_hook_register: None,
+            plt_hook_exclude: None,
+            plt_hook_commit: None,
+            connect_companion: None,
+            set_option: None,
+            get_module_dir: None,
+            get_flags: None,
+        };
+        let api = ZygiskApi::from_raw(&table.base, 2);
+
+        let (read, _write) = pipe();
+        let mut read = ModuleFd::new(read);
+        assert!(!read.exempt(&api));
+        assert!(!exempted_fds().contains(&read.as_raw_fd()));
+        assert!(ModuleFd::open_module_file(&api, c"geoink.conf", libc::O_RDONLY).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+use libc::{c_char, c_int, c_uint, c_void, dirent, size_t, ssize_t, AT_FDCWD, DIR, FILE};
+
+use crate::{
+    config, errno,
+    events::{self, Action},
+    maps, mounts,
+    plt::PltHook,
+};
+
+/// Define PLT hooks for libc functions taking a path, and a `path_hooks` function listing them.
+///
+/// All hooks ask the same question, [hidden_errno], so a path hidden from `stat` is also hidden
+/// from `open`, `opendir`, `statx` and the rest. Each hook gets its own module holding the
+/// original function pointer. When the path is hidden, the hook sets `errno` and returns `fail`
+/// without calling the original. A hook with a `virtual` expression returns what it evaluates
+/// to instead of calling the original, unless that is `None`.
+macro_rules! path_hooks {
+    ($(
+        $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty,
+        path = $path:ident, dirfd = $dirfd:expr, fail = $fail:expr $(, virtual = $virtual:expr)?;
+    )*) => {
+        $(
+            mod $name {
+                use super::*;
+
+                pub static ORIG: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+                pub extern "C" fn hook($($arg: $ty),*) -> $ret {
+                    if let Some(errno) = hidden_errno(stringify!($name), $dirfd, $path) {
+                        errno::set(errno);
+                        return $fail;
+                    }
+                    $(
+                        if let Some(ret) = $virtual {
+                            return ret;
+                        }
+                    )?
+                    let orig_ptr = ORIG.load(Ordering::Relaxed);
+                    if orig_ptr.is_null() {
+                        errno::set(libc::ENOSYS);
+                        return $fail;
+                    }
+                    let orig_fn = unsafe {
+                        std::mem::transmute::<*mut (), extern "C" fn($($ty),*) -> $ret>(orig_ptr)
+                    };
+                    orig_fn($($arg),*)
+                }
+            }
+        )*
+
+        fn path_hooks() -> Vec<PltHook> {
+            vec![$(
+                PltHook {
+                    symbol: CStr::from_bytes_with_nul(concat!(stringify!($name), "\0").as_bytes()).unwrap(),
+                    replacement: $name::hook as *mut (),
+                    orig: &$name::ORIG,
+                },
+            )*]
+        }
+    };
+}
+
+// `open` and `openat` are variadic in C. The optional `mode` is declared as a regular argument
+// instead, which is passed the same way on every ABI Android supports.
+//
+// The `open` family hands out filtered copies of memory map files and mount tables, so `read`,
+// `pread`, `mmap` and everything else done with the file descriptor see the filtered view too,
+// and `readlink` names the file a copy stands for.
+path_hooks! {
+    stat(pathname: *const c_char, buf: *mut c_void) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1;
+    lstat(pathname: *const c_char, buf: *mut c_void) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1;
+    fstatat(dirfd: c_int, pathname: *const c_char, buf: *mut c_void, flags: c_int) -> c_int,
+        path = pathname, dirfd = dirfd, fail = -1;
+    statfs(pathname: *const c_char, buf: *mut c_void) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1;
+    statx(dirfd: c_int, pathname: *const c_char, flags: c_int, mask: c_uint, buf: *mut c_void) -> c_int,
+        path = pathname, dirfd = dirfd, fail = -1;
+    access(pathname: *const c_char, mode: c_int) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1;
+    faccessat(dirfd: c_int, pathname: *const c_char, mode: c_int, flags: c_int) -> c_int,
+        path = pathname, dirfd = dirfd, fail = -1;
+    open(pathname: *const c_char, flags: c_int, mode: c_uint) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1, virtual = open_filtered(AT_FDCWD, pathname, flags);
+    openat(dirfd: c_int, pathname: *const c_char, flags: c_int, mode: c_uint) -> c_int,
+        path = pathname, dirfd = dirfd, fail = -1, virtual = open_filtered(dirfd, pathname, flags);
+    __open_2(pathname: *const c_char, flags: c_int) -> c_int,
+        path = pathname, dirfd = AT_FDCWD, fail = -1, virtual = open_filtered(AT_FDCWD, pathname, flags);
+    fopen(pathname: *const c_char, mode: *const c_char) -> *mut FILE,
+        path = pathname, dirfd = AT_FDCWD, fail = std::ptr::null_mut(), virtual = fopen_filtered(pathname, mode);
+    readlink(pathname: *const c_char, buf: *mut c_char, size: size_t) -> ssize_t,
+        path = pathname, dirfd = AT_FDCWD, fail = -1, virtual = readlink_filtered(pathname, buf, size);
+    realpath(pathname: *const c_char, resolved: *mut c_char) -> *mut c_char,
+        path = pathname, dirfd = AT_FDCWD, fail = std::ptr::null_mut();
+    opendir(pathname: *const c_char) -> *mut DIR,
+        path = pathname, dirfd = AT_FDCWD, fail = std::ptr::null_mut();
+}
+
+/// The filtered copies [open_filtered] handed out, by fd.
+static FILTERED_FDS: Mutex<Vec<FilteredFd>> = Mutex::new(Vec::new());
+
+struct FilteredFd {
+    fd: c_int,
+    /// The copy's inode, which tells it apart from a file opened later under the same fd.
+    ino: u64,
+    /// The `/proc` file it stands for.
+    path: String,
+}
+
+static ORIG_READDIR: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_READDIR64: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_GETDENTS64: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Offsets into a `struct linux_dirent64` record: `d_ino: u64, d_off: i64, d_reclen: u16,
+/// d_type: u8, d_name: [c_char]`.
+const DIRENT64_RECLEN: usize = 16;
+const DIRENT64_NAME: usize = 19;
+
+/// All file hooks, ready to be registered.
+pub fn plt_hooks() -> Vec<PltHook> {
+    let mut hooks = path_hooks();
+    hooks.push(PltHook { symbol: c"readdir", replacement: hook_readdir as *mut (), orig: &ORIG_READDIR });
+    hooks.push(PltHook { symbol: c"readdir64", replacement: hook_readdir64 as *mut (), orig: &ORIG_READDIR64 });
+    hooks.push(PltHook { symbol: c"getdents64", replacement: hook_getdents64 as *mut (), orig: &ORIG_GETDENTS64 });
+    hooks
+}
+
+extern "C" fn hook_readdir(dir: *mut DIR) -> *mut dirent {
+    filtered_readdir(&ORIG_READDIR, dir)
+}
+
+// `struct dirent64` has the same layout as `struct dirent` on every ABI Android supports
+extern "C" fn hook_readdir64(dir: *mut DIR) -> *mut dirent {
+    filtered_readdir(&ORIG_READDIR64, dir)
+}
+
+fn filtered_readdir(orig: &AtomicPtr<()>, dir: *mut DIR) -> *mut dirent {
+    let orig_ptr = orig.load(Ordering::Relaxed);
+    if orig_ptr.is_null() {
+        errno::set(libc::ENOSYS);
+        return std::ptr::null_mut();
+    }
+    let orig_fn = unsafe { std::mem::transmute::<*mut (), extern "C" fn(*mut DIR) -> *mut dirent>(orig_ptr) };
+
+    let mut dir_path = None;
+    loop {
+        let entry = orig_fn(dir);
+        if entry.is_null() {
+            return entry;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if !is_hidden_entry(unsafe { libc::dirfd(dir) }, &mut dir_path, name) {
+            return entry;
+        }
+    }
+}
+
+extern "C" fn hook_getdents64(fd: c_int, dirp: *mut c_void, count: size_t) -> ssize_t {
+    let orig_ptr = ORIG_GETDENTS64.load(Ordering::Relaxed);
+    if orig_ptr.is_null() {
+        errno::set(libc::ENOSYS);
+        return -1;
+    }
+    let orig_fn = unsafe {
+        std::mem::transmute::<*mut (), extern "C" fn(c_int, *mut c_void, size_t) -> ssize_t>(orig_ptr)
+    };
+
+    let mut dir_path = None;
+    loop {
+        let len = orig_fn(fd, dirp, count);
+        if len <= 0 || dirp.is_null() {
+            return len;
+        }
+        let buf = unsafe { std::slice::from_raw_parts_mut(dirp as *mut u8, len as usize) };
+        let kept = filter_dirents(buf, |name| is_hidden_entry(fd, &mut dir_path, name));
+        // If the whole batch was hidden, returning 0 would read as the end of the directory
+        if kept > 0 {
+            return kept as ssize_t;
+        }
+    }
+}
+
+/// Drop the entries `hide` returns true for from a buffer of `linux_dirent64` records, as filled
+/// by `getdents64`, moving the remaining ones to the front. Returns the length of what is left.
+fn filter_dirents(buf: &mut [u8], mut hide: impl FnMut(&CStr) -> bool) -> usize {
+    let (mut read, mut write) = (0, 0);
+    while read + DIRENT64_NAME < buf.len() {
+        let reclen = u16::from_ne_bytes([buf[read + DIRENT64_RECLEN], buf[read + DIRENT64_RECLEN + 1]]) as usize;
+        if reclen <= DIRENT64_NAME || read + reclen > buf.len() {
+            break;
+        }
+        let name = CStr::from_bytes_until_nul(&buf[read + DIRENT64_NAME..read + reclen]).unwrap_or_default();
+        if !hide(name) {
+            buf.copy_within(read..read + reclen, write);
+            write += reclen;
+        }
+        read += reclen;
+    }
+    write
+}
+
+/// Whether the entry `name` of the directory open as `fd` is hidden.
+///
+/// `dir_path` caches the directory's path across the entries of a single listing call, and is
+/// only resolved once an entry name could match a rule at all.
+fn is_hidden_entry(fd: c_int, dir_path: &mut Option<Option<String>>, name: &CStr) -> bool {
+    let paths = &config::active().paths;
+    let Ok(name) = name.to_str() else { return false };
+    if name == "." || name == ".." || !paths.may_hide_name(name) {
+        return false;
+    }
+
+    let dir_path = dir_path.get_or_insert_with(|| {
+        std::fs::read_link(format!("/proc/self/fd/{}", fd))
+            .ok()
+            .map(|p| p.display().to_string())
+    });
+    let Some(dir_path) = dir_path else { return false };
+
+    let path = format!("{}/{}", dir_path, name);
+    let hidden = paths.is_hidden(&path);
+    if hidden {
+        info!("Hiding dir entry: {}", path);
+        events::record("readdir", Action::Hide, path.as_str());
+    }
+    hidden
+}
+
+/// If `path` (relative to `dirfd`) is a `/proc` file the profile filters, open a filtered copy
+/// of it instead: a memfd holding its contents with the hidden entries removed.
+///
+/// Anything but a read-only open goes to the real file, as does everything if the copy can't
+/// be made.
+///
+/// The real file is opened when nothing is hidden from it, as the copy only passes for the real
+/// file as far as its content and `readlink("/proc/self/fd/<n>")` go: `fstat` reports a regular,
+/// non-empty file that isn't on procfs.
+fn open_filtered(dirfd: c_int, path: *const c_char, flags: c_int) -> Option<c_int> {
+    let active = config::active();
+    if (active.maps.is_empty() && active.mounts.is_empty())
+        || path.is_null()
+        || flags & libc::O_ACCMODE != libc::O_RDONLY
+    {
+        return None;
+    }
+    let path_str = unsafe { CStr::from_ptr(path) }.to_str().ok()?;
+    let full_path = absolute_path(dirfd, path_str);
+
+    // Our own ELF is left out of the PLT hooks (see `plt::install`), so these read the real files
+    let (filtered, removed) = if maps::is_maps_path(&full_path) && !active.maps.is_empty() {
+        active.maps.apply(&std::fs::read_to_string(&*full_path).ok()?)
+    } else if let Some(table) = mounts::mount_table(&full_path).filter(|_| !active.mounts.is_empty()) {
+        active.mounts.apply(table, &std::fs::read_to_string(&*full_path).ok()?)
+    } else {
+        return None;
+    };
+    if removed == 0 {
+        return None;
+    }
+
+    let memfd_flags = if flags & libc::O_CLOEXEC != 0 { libc::MFD_CLOEXEC } else { 0 };
+    // `memfd_create` only made it into bionic with API 30, the syscall is much older
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, c"proc".as_ptr(), memfd_flags) } as c_int;
+    if fd < 0 {
+        return None;
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(filtered.as_bytes()).ok()?;
+    file.seek(SeekFrom::Start(0)).ok()?;
+
+    let ino = file.metadata().ok()?.ino();
+    let fd = file.into_raw_fd();
+    let mut filtered_fds = FILTERED_FDS.lock().unwrap_or_else(|e| e.into_inner());
+    filtered_fds.retain(|filtered| filtered.fd != fd);
+    filtered_fds.push(FilteredFd { fd, ino, path: full_path.to_string() });
+    drop(filtered_fds);
+
+    info!("Filtered {} entries from {}", removed, full_path);
+    events::record("open", Action::Filter, full_path);
+    Some(fd)
+}
+
+/// If `path` is the `/proc/self/fd` link of a copy made by [open_filtered], fill `buf` with the
+/// path of the file it stands for rather than `/memfd:proc (deleted)`, truncated to `size` like
+/// `readlink` does.
+fn readlink_filtered(path: *const c_char, buf: *mut c_char, size: size_t) -> Option<ssize_t> {
+    if path.is_null() || buf.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(path) }.to_str().ok()?;
+    let own_fds = format!("/proc/{}/fd/", std::process::id());
+    let fd: c_int = ["/proc/self/fd/", "/proc/thread-self/fd/", &own_fds]
+        .into_iter()
+        .find_map(|prefix| path.strip_prefix(prefix))?
+        .parse()
+        .ok()?;
+
+    let filtered_fds = FILTERED_FDS.lock().unwrap_or_else(|e| e.into_inner());
+    let filtered = filtered_fds.iter().find(|filtered| filtered.fd == fd)?;
+    // The copy may have been closed since, and the fd reused
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 || stat.st_ino as u64 != filtered.ino {
+        return None;
+    }
+    let len = filtered.path.len().min(size);
+    unsafe { std::ptr::copy_nonoverlapping(filtered.path.as_ptr(), buf as *mut u8, len) };
+    Some(len as ssize_t)
+}
+
+/// [open_filtered] for `fopen`, which only applies to read-only modes.
+fn fopen_filtered(path: *const c_char, mode: *const c_char) -> Option<*mut FILE> {
+    if mode.is_null() {
+        return None;
+    }
+    let mode_str = unsafe { CStr::from_ptr(mode) }.to_bytes();
+    if !mode_str.starts_with(b"r") || mode_str.contains(&b'+') {
+        return None;
+    }
+    let cloexec = if mode_str.contains(&b'e') { libc::O_CLOEXEC } else { 0 };
+
+    let fd = open_filtered(AT_FDCWD, path, libc::O_RDONLY | cloexec)?;
+    let file = unsafe { libc::fdopen(fd, mode) };
+    if file.is_null() {
+        unsafe { libc::close(fd) };
+        return None;
+    }
+    Some(file)
+}
+
+/// If `path` (relative to `dirfd`, as in the `*at` calls) is hidden, the `errno` to fail with.
+fn hidden_errno(hook: &'static str, dirfd: c_int, path: *const c_char) -> Option<c_int> {
+    if path.is_null() {
+        return None;
+    }
+    let path_str = unsafe { CStr::from_ptr(path) }.to_str().ok()?;
+    let full_path = absolute_path(dirfd, path_str);
+
+    let errno = config::active().paths.hidden_errno(&full_path)?;
+    info!("Hiding file/dir ({}): {} -> {}", hook, full_path, errno::name(errno));
+    events::record(hook, Action::Hide, full_path);
+    Some(errno)
+}
+
+/// Resolve a relative path against `dirfd` (or the working directory for `AT_FDCWD`).
+///
+/// If the base directory can't be determined, the path is returned as is, which still lets
+/// basename rules match.
+fn absolute_path(dirfd: c_int, path: &str) -> Cow<'_, str> {
+    if path.starts_with('/') {
+        return Cow::Borrowed(path);
+    }
+    let base = if dirfd == AT_FDCWD {
+        std::env::current_dir()
+    } else {
+        std::fs::read_link(format!("/proc/self/fd/{}", dirfd))
+    };
+    match base {
+        Ok(base) => Cow::Owned(format!("{}/{}", base.display(), path)),
+        Err(_) => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::ffi::CString;
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn filter_dirents_drops_hidden_entries() {
+        let dir = std::env::temp_dir().join(format!("geoink-dirents-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["keep", "addon.d", "also-keep"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let dir_c = CString::new(dir.to_str().unwrap()).unwrap();
+        let fd = unsafe { libc::open(dir_c.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY) };
+        assert!(fd >= 0);
+        let mut buf = vec![0u8; 4096];
+        let len = unsafe { libc::syscall(libc::SYS_getdents64, fd, buf.as_mut_ptr(), buf.len()) };
+        unsafe { libc::close(fd) };
+        assert!(len > 0);
+
+        let mut buf = buf[..len as usize].to_vec();
+        let kept = filter_dirents(&mut buf, |name| name.to_bytes() == b"addon.d");
+
+        let mut names = BTreeSet::new();
+        filter_dirents(&mut buf[..kept], |name| {
+            names.insert(name.to_str().unwrap().to_string());
+            false
+        });
+        assert_eq!(names, [".", "..", "also-keep", "keep"].map(String::from).into());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hooks_fail_hidden_paths_with_the_rule_errno() {
+        let _lock = events::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        config::install_for_tests();
+        events::drain();
+        stat::ORIG.store(libc::stat as *mut (), Ordering::Relaxed);
+
+        let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+        let buf = &mut buf as *mut libc::stat as *mut c_void;
+        for (path, expected) in [("/system/addon.d", libc::ENOENT), ("/system//addon.d/50-x.sh", libc::ENOTDIR)] {
+            errno::set(0);
+            let path = CString::new(path).unwrap();
+            assert_eq!(stat::hook(path.as_ptr(), buf), -1);
+            assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(expected));
+        }
+
+        // Anything else reaches the original
+        let temp = CString::new(std::env::temp_dir().to_str().unwrap()).unwrap();
+        assert_eq!(stat::hook(temp.as_ptr(), buf), 0);
+
+        let (recorded, _) = events::drain();
+        let subjects: Vec<_> = recorded.iter().map(|e| (&*e.hook, e.action, e.subject.as_str())).collect();
+        assert_eq!(
+            subjects,
+            [("stat", Action::Hide, "/system/addon.d"), ("stat", Action::Hide, "/system//addon.d/50-x.sh")]
+        );
+    }
+
+    #[test]
+    fn only_filtered_copies_replace_proc_files() {
+        let _lock = events::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        config::install_for_tests();
+        readlink::ORIG.store(libc::readlink as *mut (), Ordering::Relaxed);
+        let maps = c"/proc/self/maps";
+
+        // Nothing to hide: the real file
+        assert_eq!(open_filtered(AT_FDCWD, maps.as_ptr(), libc::O_RDONLY), None);
+
+        let mapped = std::env::temp_dir().join(format!("geoink-maps-test-{}", std::process::id()));
+        std::fs::write(&mapped, [0u8; 4096]).unwrap();
+        let file = File::open(&mapped).unwrap();
+        let addr =
+            unsafe { libc::mmap(std::ptr::null_mut(), 4096, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        let fd = open_filtered(AT_FDCWD, maps.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC).unwrap();
+        let content = std::io::read_to_string(unsafe { File::from_raw_fd(libc::dup(fd)) }).unwrap();
+        assert!(!content.is_empty() && !content.contains("geoink-maps-test"));
+
+        let link = CString::new(format!("/proc/self/fd/{}", fd)).unwrap();
+        let mut buf = [0 as c_char; 64];
+        let len = readlink::hook(link.as_ptr(), buf.as_mut_ptr(), buf.len());
+        let name: Vec<u8> = buf[..len as usize].iter().map(|&c| c as u8).collect();
+        assert_eq!(name, b"/proc/self/maps");
+        assert_eq!(readlink::hook(link.as_ptr(), buf.as_mut_ptr(), 5), 5);
+
+        // Once the copy is closed, the fd's number is just a number again
+        unsafe { libc::close(fd) };
+        let reused = File::open(&mapped).unwrap();
+        let link = CString::new(format!("/proc/self/fd/{}", reused.as_raw_fd())).unwrap();
+        let len = readlink::hook(link.as_ptr(), buf.as_mut_ptr(), buf.len());
+        let name: Vec<u8> = buf[..len.max(0) as usize].iter().map(|&c| c as u8).collect();
+        assert_eq!(name, mapped.to_str().unwrap().as_bytes());
+
+        unsafe { libc::munmap(addr, 4096) };
+        std::fs::remove_file(mapped).unwrap();
+        events::drain();
+    }
+}
@@ -0,0 +1,21 @@
+/// Match `text` against a shell-style glob `pattern`.
+///
+/// `?` matches any single character and `*` any run of characters, neither crossing a `/`.
+/// `**` matches any run of characters including `/`. There are no character classes or escapes,
+/// as neither package names nor the paths we care about need them.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    matches_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn matches_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| matches_bytes(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let run = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=run).any(|i| matches_bytes(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && matches_bytes(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && matches_bytes(rest, tail)),
+    }
+}
@@ -0,0 +1,520 @@
+use std::ffi::{c_void, CStr};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Once, OnceLock};
+
+use jni::objects::{JObject, JValue};
+use jni::sys::{jboolean, jint, jobject, JNIEnv as RawJNIEnv, JNINativeMethod, JNI_TRUE};
+use jni::JNIEnv;
+
+use crate::{
+    args::sdk_level,
+    config,
+    events::{self, Action},
+    package_hooks::{interface_token, parcel_data, token_offset, transaction_codes},
+    profile::Profile,
+    ZygiskApi,
+};
+
+/// A JNI method hook: a replacement for `class.name(signature)`, and where to keep the original.
+pub struct JniHook {
+    pub class: &'static CStr,
+    pub name: &'static CStr,
+    pub signature: &'static CStr,
+    pub replacement: *mut c_void,
+    pub orig: &'static AtomicPtr<()>,
+}
+
+/// How the system server answers a call that hands it an intent, which decides how a blocked one
+/// is refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IntentCall {
+    /// Starts the activity of one intent.
+    Activity,
+    /// Starts the activities of an array of intents.
+    Activities,
+    /// Starts a service, replying with its `ComponentName`.
+    Service,
+    /// Binds to a service, replying with a positive number if it exists.
+    Bind,
+    /// Sends a broadcast.
+    Broadcast,
+}
+
+/// An AIDL argument a call writes ahead of its intents.
+#[derive(Clone, Copy, Debug)]
+enum Argument {
+    /// An `IBinder` or AIDL interface, such as the caller's `IApplicationThread`.
+    Binder,
+    Str,
+    /// The `callingFeatureId` string, which some calls only have since Android 11.
+    FeatureId,
+}
+
+use Argument::{Binder, FeatureId, Str};
+
+/// Every binder call through which an app can hand an `Intent` to another package, by interface
+/// and method, with the arguments that come before the intent. The same calls moved from
+/// `IActivityManager` to `IActivityTaskManager` in Android 10, and each version only has some
+/// of them.
+const INTENT_TRANSACTIONS: &[(&str, &str, IntentCall, &[Argument])] = &[
+    ("android.app.IActivityTaskManager", "startActivity", IntentCall::Activity, &[Binder, Str, FeatureId]),
+    ("android.app.IActivityTaskManager", "startActivityAsUser", IntentCall::Activity, &[Binder, Str, FeatureId]),
+    ("android.app.IActivityTaskManager", "startActivities", IntentCall::Activities, &[Binder, Str, FeatureId]),
+    ("android.app.IActivityManager", "startActivity", IntentCall::Activity, &[Binder, Str]),
+    ("android.app.IActivityManager", "startActivityAsUser", IntentCall::Activity, &[Binder, Str]),
+    ("android.app.IActivityManager", "startActivityWithFeature", IntentCall::Activity, &[Binder, Str, Str]),
+    ("android.app.IActivityManager", "startActivityAsUserWithFeature", IntentCall::Activity, &[Binder, Str, Str]),
+    ("android.app.IActivityManager", "startActivities", IntentCall::Activities, &[Binder, Str]),
+    ("android.app.IActivityManager", "startService", IntentCall::Service, &[Binder]),
+    ("android.app.IActivityManager", "bindService", IntentCall::Bind, &[Binder, Binder]),
+    ("android.app.IActivityManager", "bindIsolatedService", IntentCall::Bind, &[Binder, Binder]),
+    ("android.app.IActivityManager", "bindServiceInstance", IntentCall::Bind, &[Binder, Binder]),
+    ("android.app.IActivityManager", "broadcastIntent", IntentCall::Broadcast, &[Binder]),
+    ("android.app.IActivityManager", "broadcastIntentWithFeature", IntentCall::Broadcast, &[Binder, Str]),
+];
+
+/// One of [INTENT_TRANSACTIONS], as the running Android version numbers it.
+struct IntentTransaction {
+    interface: &'static str,
+    name: &'static str,
+    code: jint,
+    call: IntentCall,
+    arguments: &'static [Argument],
+}
+
+static INTENT_CODES: OnceLock<Vec<IntentTransaction>> = OnceLock::new();
+
+/// `ActivityManager.START_INTENT_NOT_RESOLVED`, which `Instrumentation` turns into an
+/// `ActivityNotFoundException`.
+const START_INTENT_NOT_RESOLVED: jint = -91;
+
+/// `IBinder.FLAG_ONEWAY`: the caller doesn't wait for a reply.
+const FLAG_ONEWAY: jint = 1;
+
+/// Refuse intents addressed to the profile's blocked packages.
+///
+/// Starting activities and services, binding and broadcasting are all Java all the way down to
+/// `BinderProxy.transactNative`, so the transaction codes of those calls are looked up here, and
+/// [refuse_blocked_intent] checks them in the hook `package_hooks` installs there.
+pub unsafe fn apply(_api: &ZygiskApi, env: &mut JNIEnv) {
+    if config::active().profile.blocked_packages.is_empty() {
+        return;
+    }
+
+    let mut resolved = Vec::new();
+    for &(interface, name, call, arguments) in INTENT_TRANSACTIONS {
+        let stub = format!("{}$Stub", interface.replace('.', "/"));
+        let Some(codes) = transaction_codes(env, &stub, &[&format!("TRANSACTION_{}", name)]) else {
+            let _ = env.exception_clear();
+            continue;
+        };
+        resolved.extend(codes.first().map(|&code| IntentTransaction { interface, name, code, call, arguments }));
+    }
+    if resolved.is_empty() {
+        return error!("Unable to resolve any intent transaction, intents won't be blocked");
+    }
+    info!("Resolved {} intent transactions", resolved.len());
+    let _ = INTENT_CODES.set(resolved);
+}
+
+/// Hook each method separately, so that one missing method doesn't take the others down with it.
+pub unsafe fn register(api: &ZygiskApi, env: &mut JNIEnv, hooks: &[JniHook]) {
+    for hook in hooks {
+        let mut methods = [JNINativeMethod {
+            name: hook.name.as_ptr() as *mut _,
+            signature: hook.signature.as_ptr() as *mut _,
+            fnPtr: hook.replacement,
+        }];
+
+        api.hook_jni_native_methods(*env, hook.class, &mut methods);
+
+        let orig_ptr = methods[0].fnPtr;
+        if !orig_ptr.is_null() {
+            hook.orig.store(orig_ptr as *mut (), Ordering::Relaxed);
+            info!("Successfully hooked {:?}.{:?}", hook.class, hook.name);
+        } else {
+            error!("Failed to hook {:?}.{:?}", hook.class, hook.name);
+        }
+    }
+}
+
+/// Answer a binder call handing an intent to a blocked package the way the system server answers
+/// one that nothing handles, without making it. Returns what `transactNative` should return, or
+/// `None` if the call has to go through.
+///
+/// Any other call is told apart by its code, and by its interface token when the code matches.
+pub fn refuse_blocked_intent(env: &JNIEnv, code: jint, data: jobject, reply: jobject, flags: jint) -> Option<jboolean> {
+    let transactions = INTENT_CODES.get()?;
+    let candidates: Vec<&IntentTransaction> = transactions.iter().filter(|t| t.code == code).collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let interfaces: Vec<&'static str> = candidates.iter().map(|t| t.interface).collect();
+    let interface = interface_token(env, data, &interfaces)?;
+    let transaction = candidates.into_iter().find(|t| t.interface == interface)?;
+
+    let sdk = sdk_level();
+    let offsets = match intent_offsets(env, JObject::from(data), transaction, sdk) {
+        Ok(offsets) => offsets,
+        Err(_) => {
+            let _ = env.exception_clear();
+            return warn_unreadable(transaction);
+        }
+    };
+    if offsets.is_empty() {
+        // A null intent, which the system server refuses by itself
+        return None;
+    }
+    // Activity calls carry the caller's binder, which `Parcel.marshall` refuses to copy
+    let Some(data) = parcel_data(env, data) else { return warn_unreadable(transaction) };
+    let targets: Option<Vec<IntentTarget>> =
+        offsets.into_iter().map(|pos| ParcelReader { data: &data, pos }.intent(sdk)).collect();
+    let Some(targets) = targets else { return warn_unreadable(transaction) };
+    let package = blocked_target(&config::active().profile, &targets)?;
+
+    info!("GeoInk-Core: Blocked {} to {}", transaction.name, package);
+    events::record(transaction.name, Action::Block, package);
+    if !reply.is_null() && flags & FLAG_ONEWAY == 0 {
+        let result = match transaction.call {
+            IntentCall::Activity | IntentCall::Activities => START_INTENT_NOT_RESOLVED,
+            // A null `ComponentName`, no binding, or a successful broadcast nobody received
+            IntentCall::Service | IntentCall::Bind | IntentCall::Broadcast => 0,
+        };
+        write_reply(env, JObject::from(reply), result);
+    }
+    Some(JNI_TRUE)
+}
+
+fn write_reply(env: &JNIEnv, reply: JObject, result: jint) {
+    let written = env
+        .call_method(reply, "writeNoException", "()V", &[])
+        .and_then(|_| env.call_method(reply, "writeInt", "(I)V", &[JValue::Int(result)]))
+        .and_then(|_| env.call_method(reply, "setDataPosition", "(I)V", &[JValue::Int(0)]));
+    if written.is_err() {
+        let _ = env.exception_clear();
+    }
+}
+
+/// Let the call through when its intents can't be read, which only ever happens because this
+/// Android version lays them out differently, so every call would fail the same way: say so once.
+fn warn_unreadable(transaction: &IntentTransaction) -> Option<jboolean> {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| warn!("Unable to read the intent of {}, intents may not be blocked", transaction.name));
+    None
+}
+
+/// Where the intents of a call start in its data.
+///
+/// The interface token and the arguments ahead of the intents are read with `Parcel` itself, so
+/// binders are skipped whatever their size. The parcel's position is left as it was.
+fn intent_offsets(
+    env: &JNIEnv,
+    parcel: JObject,
+    transaction: &IntentTransaction,
+    sdk: i32,
+) -> jni::errors::Result<Vec<usize>> {
+    let saved = env.call_method(parcel, "dataPosition", "()I", &[])?.i()?;
+    let offsets = read_intent_offsets(env, parcel, transaction, sdk);
+    if offsets.is_err() {
+        let _ = env.exception_clear();
+    }
+    env.call_method(parcel, "setDataPosition", "(I)V", &[JValue::Int(saved)])?;
+    offsets
+}
+
+fn read_intent_offsets(
+    env: &JNIEnv,
+    parcel: JObject,
+    transaction: &IntentTransaction,
+    sdk: i32,
+) -> jni::errors::Result<Vec<usize>> {
+    let read = |name, sig| env.call_method(parcel, name, sig, &[]);
+    let skip = |name, sig| read(name, sig)?.l().and_then(|value| env.delete_local_ref(value));
+
+    env.call_method(parcel, "setDataPosition", "(I)V", &[JValue::Int(token_offset(sdk))])?;
+    skip("readString", "()Ljava/lang/String;")?;
+    for argument in transaction.arguments {
+        match argument {
+            Argument::Binder => skip("readStrongBinder", "()Landroid/os/IBinder;")?,
+            Argument::FeatureId if sdk < 30 => {}
+            Argument::Str | Argument::FeatureId => skip("readString", "()Ljava/lang/String;")?,
+        }
+    }
+
+    // Each intent is preceded by 1, or is a 0 for `null`. An array is preceded by its length.
+    let count = match transaction.call {
+        IntentCall::Activities => read("readInt", "()I")?.i()?.max(0),
+        _ => 1,
+    };
+    let mut offsets = Vec::new();
+    for i in 0..count {
+        if read("readInt", "()I")?.i()? == 0 {
+            continue;
+        }
+        offsets.push(read("dataPosition", "()I")?.i()? as usize);
+        if i + 1 < count {
+            // Nothing but the intent itself knows where it ends
+            let creator = env.get_static_field("android/content/Intent", "CREATOR", "Landroid/os/Parcelable$Creator;")?;
+            let args = [JValue::Object(parcel)];
+            let sig = "(Landroid/os/Parcel;)Ljava/lang/Object;";
+            let intent = env.call_method(creator.l()?, "createFromParcel", sig, &args)?;
+            env.delete_local_ref(creator.l()?)?;
+            env.delete_local_ref(intent.l()?)?;
+        }
+    }
+    Ok(offsets)
+}
+
+/// The package the intents of a call are addressed to, if the profile blocks it.
+///
+/// Both explicit intents (their component) and implicit intents limited to a single package
+/// are checked.
+fn blocked_target(profile: &Profile, targets: &[IntentTarget]) -> Option<String> {
+    targets
+        .iter()
+        .flat_map(|target| [&target.component_package, &target.package])
+        .flatten()
+        .find(|package| profile.blocks_package(package))
+        .cloned()
+}
+
+/// Who an intent is addressed to.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct IntentTarget {
+    /// `getComponent().getPackageName()`
+    component_package: Option<String>,
+    /// `getPackage()`
+    package: Option<String>,
+}
+
+/// How `Parcel` wrote a string.
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// `writeString`
+    Utf16,
+    /// `writeString8`
+    Utf8,
+}
+
+/// A reader over marshalled parcel data, which fails on anything that doesn't read as what it
+/// expects rather than guessing.
+struct ParcelReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ParcelReader<'_> {
+    fn int(&mut self) -> Option<i32> {
+        let bytes = self.data.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// A nullable string: a length (`-1` for `null`), the characters and a terminator, padded
+    /// to 4 bytes.
+    fn string(&mut self, encoding: Encoding) -> Option<Option<String>> {
+        let len = self.int()?;
+        if len == -1 {
+            return Some(None);
+        }
+        let len = usize::try_from(len).ok()?;
+        let (size, terminator) = match encoding {
+            Encoding::Utf16 => (len.checked_mul(2)?, 2),
+            Encoding::Utf8 => (len, 1),
+        };
+        let bytes = self.data.get(self.pos..self.pos.checked_add(size + terminator)?)?;
+        if bytes[size..].iter().any(|&b| b != 0) {
+            return None;
+        }
+        let string = match encoding {
+            Encoding::Utf16 => {
+                let units: Vec<u16> = bytes[..size].chunks_exact(2).map(|u| u16::from_le_bytes([u[0], u[1]])).collect();
+                String::from_utf16(&units).ok()?
+            }
+            Encoding::Utf8 => String::from_utf8(bytes[..size].to_vec()).ok()?,
+        };
+        if string.contains('\0') {
+            return None;
+        }
+        self.pos += (size + terminator).next_multiple_of(4);
+        Some(Some(string))
+    }
+
+    /// The fields `Intent.writeToParcel` starts with, up to its component.
+    fn intent(&mut self, sdk: i32) -> Option<IntentTarget> {
+        // Intents write their strings as UTF-8 since Android 12
+        let encoding = if sdk >= 31 { Encoding::Utf8 } else { Encoding::Utf16 };
+        self.string(encoding)?; // action
+        self.uri(encoding)?; // data
+        self.string(encoding)?; // type
+        if sdk >= 29 {
+            self.string(encoding)?; // identifier
+        }
+        self.int()?; // flags
+        if sdk >= 34 {
+            self.int()?; // extended flags
+        }
+        let package = self.string(encoding)?;
+        let component_package = self.component()?;
+        Some(IntentTarget { component_package, package })
+    }
+
+    /// `Uri.writeToParcel`: a type, then the string of a `StringUri` or the parts of an
+    /// `OpaqueUri` or `HierarchicalUri`.
+    fn uri(&mut self, encoding: Encoding) -> Option<()> {
+        let parts = match self.int()? {
+            0 => return Some(()), // null
+            1 => 0,
+            2 => 2, // scheme-specific part, fragment
+            3 => 4, // authority, path, query, fragment
+            _ => return None,
+        };
+        self.string(encoding)?; // the string, or the scheme
+        for _ in 0..parts {
+            // A representation, followed by the encoded and decoded value or just one of them
+            let strings = match self.int()? {
+                0 => 2,
+                1 | 2 => 1,
+                _ => return None,
+            };
+            for _ in 0..strings {
+                self.string(encoding)?;
+            }
+        }
+        Some(())
+    }
+
+    /// `ComponentName.writeToParcel`: `null`, or the package and the class. Always UTF-16.
+    fn component(&mut self) -> Option<Option<String>> {
+        let Some(package) = self.string(Encoding::Utf16)? else { return Some(None) };
+        self.string(Encoding::Utf16)??;
+        Some(Some(package))
+    }
+}
+
+pub fn call_object<'a>(env: &JNIEnv<'a>, obj: JObject<'a>, name: &str, sig: &str) -> Option<JObject<'a>> {
+    match env.call_method(obj, name, sig, &[]).and_then(|v| v.l()) {
+        Ok(result) => Some(result),
+        Err(_) => {
+            // Don't leave a pending exception behind for the caller to trip over
+            let _ = env.exception_clear();
+            None
+        }
+    }
+}
+
+pub fn env_from_raw<'a>(env: *mut RawJNIEnv) -> Option<JNIEnv<'a>> {
+    unsafe { JNIEnv::from_raw(env) }.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, s: Option<&str>, encoding: Encoding) {
+        let Some(s) = s else { return out.extend((-1i32).to_le_bytes()) };
+        match encoding {
+            Encoding::Utf16 => {
+                let units: Vec<u16> = s.encode_utf16().collect();
+                out.extend((units.len() as i32).to_le_bytes());
+                out.extend(units.iter().flat_map(|u| u.to_le_bytes()));
+                out.extend([0, 0]);
+            }
+            Encoding::Utf8 => {
+                out.extend((s.len() as i32).to_le_bytes());
+                out.extend(s.as_bytes());
+                out.push(0);
+            }
+        }
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    /// A non-null intent argument: 1, then what `Intent.writeToParcel` writes on `sdk`.
+    fn intent(out: &mut Vec<u8>, sdk: i32, package: Option<&str>, component: Option<(&str, &str)>) {
+        let encoding = if sdk >= 31 { Encoding::Utf8 } else { Encoding::Utf16 };
+        out.extend(1i32.to_le_bytes());
+        string(out, Some("android.intent.action.VIEW"), encoding);
+        // A hierarchical URI, with an encoded authority and both representations of the path
+        out.extend(3i32.to_le_bytes());
+        string(out, Some("https"), encoding);
+        out.extend(1i32.to_le_bytes());
+        string(out, Some("example.com"), encoding);
+        out.extend(0i32.to_le_bytes());
+        string(out, Some("/a%20b"), encoding);
+        string(out, Some("/a b"), encoding);
+        out.extend(2i32.to_le_bytes());
+        string(out, None, encoding);
+        out.extend(2i32.to_le_bytes());
+        string(out, None, encoding);
+        string(out, None, encoding); // type
+        if sdk >= 29 {
+            string(out, None, encoding); // identifier
+        }
+        out.extend(0x1000_0000i32.to_le_bytes()); // flags
+        if sdk >= 34 {
+            out.extend(1i32.to_le_bytes()); // extended flags: EXTENDED_FLAG_FILTER_MISMATCH
+        }
+        string(out, package, encoding);
+        match component {
+            Some((package, class)) => {
+                string(out, Some(package), Encoding::Utf16);
+                string(out, Some(class), Encoding::Utf16);
+            }
+            None => string(out, None, Encoding::Utf16),
+        }
+        out.extend(0i32.to_le_bytes()); // source bounds
+        out.extend(0i32.to_le_bytes()); // categories
+        out.extend(0i32.to_le_bytes()); // selector
+        out.extend(0i32.to_le_bytes()); // clip data
+        out.extend((-2i32).to_le_bytes()); // content user hint
+        out.extend((-1i32).to_le_bytes()); // extras
+    }
+
+    /// The target of the intent argument at the start of `data`.
+    fn read(data: &[u8], sdk: i32) -> Option<IntentTarget> {
+        let mut reader = ParcelReader { data, pos: 0 };
+        assert_eq!(reader.int(), Some(1));
+        reader.intent(sdk)
+    }
+
+    fn target(component_package: Option<&str>, package: Option<&str>) -> IntentTarget {
+        IntentTarget { component_package: component_package.map(Into::into), package: package.map(Into::into) }
+    }
+
+    #[test]
+    fn intent_targets_are_read_on_every_version() {
+        for sdk in [28, 29, 30, 31, 33, 34, 35] {
+            let mut data = Vec::new();
+            intent(&mut data, sdk, None, Some(("com.sukisu.ultra", "com.sukisu.ultra.Main")));
+            assert_eq!(read(&data, sdk), Some(target(Some("com.sukisu.ultra"), None)), "SDK {}", sdk);
+
+            let mut data = Vec::new();
+            intent(&mut data, sdk, Some("com.sukisu.ultra"), None);
+            assert_eq!(read(&data, sdk), Some(target(None, Some("com.sukisu.ultra"))), "SDK {}", sdk);
+        }
+    }
+
+    #[test]
+    fn extended_flags_come_before_the_package_since_android_14() {
+        let mut data = Vec::new();
+        intent(&mut data, 34, Some("com.sukisu.ultra"), None);
+        assert_eq!(read(&data, 34), Some(target(None, Some("com.sukisu.ultra"))));
+        // Read with the layout of Android 13, the extended flags would be taken for the package
+        assert_ne!(read(&data, 33), Some(target(None, Some("com.sukisu.ultra"))));
+    }
+
+    #[test]
+    fn intents_to_blocked_packages_are_blocked() {
+        let profile = Profile { blocked_packages: vec!["com.sukisu.ultra".to_string()], ..Profile::new("test") };
+        let blocked = |targets: &[IntentTarget]| blocked_target(&profile, targets);
+
+        assert_eq!(blocked(&[target(Some("com.sukisu.ultra"), None)]).as_deref(), Some("com.sukisu.ultra"));
+        assert_eq!(blocked(&[target(None, Some("com.sukisu.ultra"))]).as_deref(), Some("com.sukisu.ultra"));
+        assert_eq!(blocked(&[target(Some("com.example.other"), Some("com.example.other"))]), None);
+        assert_eq!(blocked(&[target(None, Some("com.sukisu.ultra.extra"))]), None);
+        assert_eq!(blocked(&[target(None, None)]), None);
+        // Any intent of an array
+        let targets = [target(None, Some("com.example.other")), target(Some("com.sukisu.ultra"), None)];
+        assert_eq!(blocked(&targets).as_deref(), Some("com.sukisu.ultra"));
+    }
+}
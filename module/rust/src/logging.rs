@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::{fd::ModuleFd, ZygiskApi};
+
+/// Name of the log file of the `file` backend, relative to the module root directory. The
+/// companion writes the lines of the `companion` backend to it as well.
+pub const LOG_FILE: &CStr = c"geoink.log";
+
+/// How many lines the `companion` backend keeps until they are flushed.
+const CAPACITY: usize = 256;
+
+/// Where the `info!`, `error!`, ... of the module go.
+///
+/// Anything written to logcat from a target app can be read back by the app, so only debug
+/// builds use it by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogBackend {
+    /// Nothing is logged.
+    Off,
+    Logcat,
+    /// Lines are sent to the companion, which appends them to [LOG_FILE]. Lines logged before
+    /// specialization, or without a companion connection, are buffered in the process.
+    Companion,
+    /// Lines are appended to [LOG_FILE] from the process itself, which only works on runtimes
+    /// that can keep the file open across specialization (API v4 and later).
+    File,
+}
+
+impl LogBackend {
+    /// Debug builds log to logcat, release builds don't log at all.
+    pub const DEFAULT: LogBackend = if cfg!(debug_assertions) { LogBackend::Logcat } else { LogBackend::Off };
+
+    const ALL: [LogBackend; 4] = [LogBackend::Off, LogBackend::Logcat, LogBackend::Companion, LogBackend::File];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogBackend::Off => "off",
+            LogBackend::Logcat => "logcat",
+            LogBackend::Companion => "companion",
+            LogBackend::File => "file",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<LogBackend, String> {
+        LogBackend::ALL
+            .into_iter()
+            .find(|backend| backend.name() == value)
+            .ok_or_else(|| format!("unknown log backend `{}`, expected off, logcat, companion or file", value))
+    }
+}
+
+/// A line logged through the `companion` backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLine {
+    /// Seconds since the epoch.
+    pub time: u64,
+    /// `<LEVEL> <message>`.
+    pub text: String,
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(LogBackend::DEFAULT as u8);
+static PENDING: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+static FILE: OnceLock<(File, String)> = OnceLock::new();
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        backend() != LogBackend::Off && metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match backend() {
+            LogBackend::Off => {}
+            LogBackend::Logcat => logcat(record),
+            LogBackend::Companion => {
+                let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+                if pending.len() == CAPACITY {
+                    pending.pop_front();
+                }
+                pending.push_back(LogLine { time: now(), text: format!("{} {}", record.level(), record.args()) });
+            }
+            LogBackend::File => {
+                if let Some((file, process)) = FILE.get() {
+                    // The same format the companion writes, in a single write so lines from
+                    // different threads don't interleave
+                    let pid = std::process::id();
+                    let line = format!("{} {} {} {} {}\n", now(), pid, process, record.level(), record.args());
+                    let _ = (&*file).write_all(line.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(target_os = "android")]
+fn logcat(record: &Record) {
+    use android_logger::{AndroidLogger, Config};
+
+    static LOGCAT: OnceLock<AndroidLogger> = OnceLock::new();
+    let logcat = LOGCAT.get_or_init(|| {
+        AndroidLogger::new(Config::default().with_min_level(log::Level::Info).with_tag("zygisk_geoink_core"))
+    });
+    logcat.log(record);
+}
+
+#[cfg(not(target_os = "android"))]
+fn logcat(record: &Record) {
+    eprintln!("{} {}", record.level(), record.args());
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Install the logger. Where lines go is decided by [set_backend], [LogBackend::DEFAULT] until then.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+pub fn backend() -> LogBackend {
+    LogBackend::ALL[BACKEND.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_backend(backend: LogBackend) {
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+}
+
+/// Switch to `backend` for the rest of this process, doing what it needs before specialization:
+/// [LogBackend::File] opens the log file and keeps it open, falling back to [LogBackend::Off] if
+/// it can't.
+///
+/// This only works in `pre[XXX]Specialize`.
+pub fn configure(api: &ZygiskApi, backend: LogBackend, process: &str) {
+    if backend == LogBackend::File && FILE.get().is_none() {
+        let flags = libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT;
+        let mut fd = match ModuleFd::open_module_file(api, LOG_FILE, flags) {
+            Ok(fd) => fd,
+            Err(_) => return set_backend(LogBackend::Off),
+        };
+        if !fd.exempt(api) {
+            return set_backend(LogBackend::Off);
+        }
+        let _ = FILE.set((File::from(fd.into_owned()), process.to_string()));
+    }
+    set_backend(backend);
+}
+
+/// Take the lines the `companion` backend buffered.
+pub fn drain() -> Vec<LogLine> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_parse() {
+        for backend in LogBackend::ALL {
+            assert_eq!(LogBackend::parse(backend.name()), Ok(backend));
+            assert_eq!(LogBackend::ALL[backend as usize], backend);
+        }
+        assert!(LogBackend::parse("stderr").is_err());
+        assert_eq!(LogBackend::DEFAULT == LogBackend::Off, !cfg!(debug_assertions));
+    }
+}
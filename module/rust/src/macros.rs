@@ -1,7 +1,7 @@
 pub use jni::JNIEnv;
 
 use crate::{
-    binding::{ModuleAbi, RawApiTable},
+    binding::{ModuleAbi, RawApiTable, SUPPORTED_API_VERSIONS},
     module::RawModule,
     ZygiskApi, ZygiskModule,
 };
@@ -20,12 +20,21 @@ pub fn module_entry_impl(module: &'static dyn ZygiskModule, table: *const (), en
     let raw_module = Box::leak(Box::new(RawModule {
         inner: module,
         api_table: table,
+        api_version: SUPPORTED_API_VERSIONS[0],
         // Save JNIEnv here
-        env: unsafe { std::mem::transmute(env.get_native_interface()) },
+        env: env.get_native_interface(),
     }));
     let module_abi = Box::leak(Box::new(ModuleAbi::from_module(raw_module)));
-    if table.register_module.unwrap()(table, module_abi) {
-        let api = ZygiskApi::from_raw(table);
+
+    // The runtime refuses versions newer than its own, so go down the list until one sticks
+    let register_module = table.register_module.unwrap();
+    let registered = SUPPORTED_API_VERSIONS.iter().copied().find(|&version| {
+        module_abi.api_version = version;
+        module_abi.this.api_version = version;
+        register_module(table, module_abi)
+    });
+    if let Some(version) = registered {
+        let api = ZygiskApi::from_raw(table, version);
         // Use the pre-built env `mut`
         module.on_load(api, &mut env);
     }
@@ -0,0 +1,128 @@
+use regex_lite::Regex;
+
+use crate::paths::proc_file_name;
+
+/// Lines to remove from the memory map files of `/proc`, as unanchored regular expressions
+/// matched against a whole `maps` line, e.g. `libgeoink_core\.so` or `/memfd:.*\.so`.
+///
+/// In `smaps`, a removed mapping takes all of its detail lines (`Size:`, `Rss:`, ...) along.
+#[derive(Debug, Default)]
+pub struct MapsFilter {
+    patterns: Vec<Regex>,
+}
+
+impl MapsFilter {
+    pub fn new(patterns: &[String]) -> MapsFilter {
+        // Patterns were validated when the config was parsed
+        MapsFilter { patterns: patterns.iter().filter_map(|p| Regex::new(p).ok()).collect() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    fn hides(&self, line: &str) -> bool {
+        self.patterns.iter().any(|p| p.is_match(line))
+    }
+
+    /// Filter the contents of a `maps` or `smaps` file, returning the filtered text and the
+    /// number of mappings removed.
+    pub fn apply(&self, text: &str) -> (String, usize) {
+        let mut out = String::with_capacity(text.len());
+        let mut removed = 0;
+        let mut keep = true;
+        for line in text.split_inclusive('\n') {
+            if is_mapping(line) {
+                keep = !self.hides(line);
+                removed += usize::from(!keep);
+            }
+            if keep {
+                out.push_str(line);
+            }
+        }
+        (out, removed)
+    }
+}
+
+/// Whether a line starts a mapping (`<start>-<end> <perms> ...`) rather than being one of the
+/// detail lines `smaps` adds below it.
+fn is_mapping(line: &str) -> bool {
+    let range = line.split(' ').next().unwrap_or_default();
+    let Some((start, end)) = range.split_once('-') else { return false };
+    let is_hex = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit());
+    is_hex(start) && is_hex(end)
+}
+
+/// Whether `path` is a memory map file of some process or thread: `/proc/<pid>/maps`,
+/// `/proc/self/smaps`, `/proc/thread-self/maps`, `/proc/<pid>/task/<tid>/smaps` and so on.
+pub fn is_maps_path(path: &str) -> bool {
+    matches!(proc_file_name(path).as_deref(), Some("maps" | "smaps"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = include_str!("../testdata/maps");
+    const SMAPS: &str = include_str!("../testdata/smaps");
+
+    fn filter() -> MapsFilter {
+        MapsFilter::new(&["zygisk".to_string(), "magisk".to_string(), r"libgeoink_core\.so".to_string()])
+    }
+
+    #[test]
+    fn maps_lines_are_removed() {
+        let (filtered, removed) = filter().apply(MAPS);
+        assert_eq!(removed, 6);
+        assert_eq!(filtered.lines().count(), MAPS.lines().count() - 6);
+        assert!(!filtered.contains("zygisk") && !filtered.contains("magisk") && !filtered.contains("geoink"));
+        // Everything else is untouched, including anonymous mappings and the trailing newline
+        assert!(filtered.contains("7d4e1c2000-7d4e1c3000 rw-p 00000000 00:00 0 \n"));
+        assert!(filtered.contains("[anon:dalvik-main space (region space)]"));
+        assert!(filtered.ends_with("[vdso]\n"));
+    }
+
+    #[test]
+    fn smaps_entries_are_removed_whole() {
+        let (filtered, removed) = filter().apply(SMAPS);
+        assert_eq!(removed, 2);
+        assert!(!filtered.contains("libzygisk") && !filtered.contains("geoink"));
+        // Each remaining mapping keeps all of its detail lines
+        let mappings = filtered.lines().filter(|l| is_mapping(l)).count();
+        assert_eq!(mappings, 3);
+        assert_eq!(filtered.lines().filter(|l| l.starts_with("VmFlags:")).count(), 3);
+        assert!(filtered.starts_with("5f2a3b4000-5f2a3b6000 r--p"));
+    }
+
+    #[test]
+    fn memfd_libraries_can_be_hidden() {
+        let filter = MapsFilter::new(&[r"/memfd:.*\.so".to_string()]);
+        let (filtered, removed) = filter.apply(MAPS);
+        assert_eq!(removed, 2);
+        // ART's JIT cache is memfd-backed too, and must stay
+        assert!(filtered.contains("/memfd:jit-cache (deleted)"));
+    }
+
+    #[test]
+    fn an_empty_filter_changes_nothing() {
+        let (filtered, removed) = MapsFilter::default().apply(SMAPS);
+        assert_eq!((filtered.as_str(), removed), (SMAPS, 0));
+    }
+
+    #[test]
+    fn maps_paths() {
+        for path in [
+            "/proc/self/maps",
+            "/proc/self/smaps",
+            "/proc/thread-self/maps",
+            "/proc/1234/maps",
+            "/proc/1234/task/1240/smaps",
+            "/proc//self/./maps",
+        ] {
+            assert!(is_maps_path(path), "{}", path);
+        }
+        for path in ["/proc/self/smaps_rollup", "/proc/self/mapsx", "/proc/self/task/maps", "/proc/maps", "/data/maps"] {
+            assert!(!is_maps_path(path), "{}", path);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::os::raw::{c_long, c_void};
+
 use jni::sys::JNIEnv as RawJNIEnv;
 use jni::JNIEnv;
 
@@ -34,15 +36,17 @@ pub trait ZygiskModule {
     /// This function is called after the app process is specialized.
     /// At this point, the process has all sandbox restrictions enabled for this application.
     /// This means that this function runs as the same privilege of the app's own code.
-    fn post_app_specialize(&self, api: ZygiskApi, args: &AppSpecializeArgs) {}
+    ///
+    /// JNI calls made through `env` are subject to the app's hidden API restrictions.
+    fn post_app_specialize(&self, api: ZygiskApi, args: &AppSpecializeArgs, env: &mut JNIEnv) {}
 
     /// This function is called before the system server process is specialized.
     /// See [Self::pre_app_specialize] for more info.
-    fn pre_server_specialize(&self, api: ZygiskApi, args: &mut ServerSpecializeArgs) {}
+    fn pre_server_specialize(&self, api: ZygiskApi, args: &mut ServerSpecializeArgs, env: &mut JNIEnv) {}
 
     /// This function is called after the system server process is specialized.
     /// At this point, the process runs with the privilege of `system_server`.
-    fn post_server_specialize(&self, api: ZygiskApi, args: &ServerSpecializeArgs) {}
+    fn post_server_specialize(&self, api: ZygiskApi, args: &ServerSpecializeArgs, env: &mut JNIEnv) {}
 }
 
 /// Information about a registered module, for use in FFI functions.
@@ -52,34 +56,46 @@ pub trait ZygiskModule {
 pub(crate) struct RawModule {
     pub inner: &'static dyn ZygiskModule,
     pub api_table: *const RawApiTable,
+    /// The API version the module registered with, which determines the layout of the API
+    /// table and the arguments.
+    pub api_version: c_long,
     // Add a field to store JNIEnv
     pub env: *mut RawJNIEnv,
 }
 
 impl crate::binding::ModuleAbi {
     pub(crate) fn from_module(module: &'static mut RawModule) -> ModuleAbi {
+        // All callbacks run on the thread the module was loaded on, so the env saved then is
+        // still the right one
         macro_rules! def_func {
             ($name: ident, $arg_type: ty) => {
                 extern "C" fn $name(module: &mut RawModule, args: $arg_type) {
-                    let api = unsafe { ZygiskApi::from_raw(&*module.api_table) };
-                    module.inner.$name(api, args);
+                    let api = unsafe { ZygiskApi::from_raw(&*module.api_table, module.api_version) };
+                    // Recreate the JNIEnv from the pointers we saved
+                    let mut env = unsafe { JNIEnv::from_raw(module.env).unwrap() };
+                    module.inner.$name(api, args, &mut env);
                 }
             };
         }
-        def_func!(post_app_specialize, &AppSpecializeArgs);
         def_func!(pre_server_specialize, &mut ServerSpecializeArgs);
         def_func!(post_server_specialize, &ServerSpecializeArgs);
-        
-        // Modify this external function
-        extern "C" fn pre_app_specialize(module: &mut RawModule, args: &mut AppSpecializeArgs) {
-            let api = unsafe { ZygiskApi::from_raw(&*module.api_table) };
-            // Recreate the JNIEnv from the pointers we saved
+
+        // The app arguments are laid out differently depending on the API version
+        extern "C" fn pre_app_specialize(module: &mut RawModule, args: *mut c_void) {
+            let api = unsafe { ZygiskApi::from_raw(&*module.api_table, module.api_version) };
+            let mut env = unsafe { JNIEnv::from_raw(module.env).unwrap() };
+            let mut args = unsafe { AppSpecializeArgs::from_raw(args, module.api_version) };
+            module.inner.pre_app_specialize(api, &mut args, &mut env);
+        }
+        extern "C" fn post_app_specialize(module: &mut RawModule, args: *const c_void) {
+            let api = unsafe { ZygiskApi::from_raw(&*module.api_table, module.api_version) };
             let mut env = unsafe { JNIEnv::from_raw(module.env).unwrap() };
-            module.inner.pre_app_specialize(api, args, &mut env);
+            let args = unsafe { AppSpecializeArgs::from_raw(args, module.api_version) };
+            module.inner.post_app_specialize(api, &args, &mut env);
         }
 
         ModuleAbi {
-            api_version: crate::API_VERSION,
+            api_version: module.api_version,
             this: module,
             pre_app_specialize,
             post_app_specialize,
@@ -0,0 +1,302 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    glob,
+    paths::{normalize, proc_file_name},
+};
+
+/// The part of a mount entry a [MountRule] looks at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountField {
+    /// The mounted device or filesystem name, e.g. `/dev/block/dm-6` or `magisk`.
+    Source,
+    /// Where it is mounted.
+    Target,
+    FsType,
+    /// The directory of the source filesystem that is mounted, which is how bind mounts give
+    /// themselves away. Only `mountinfo` has it, so these rules never match `/proc/mounts`.
+    Root,
+}
+
+/// A mount hiding rule, written in the config as `<field>:<glob>` with the field one of
+/// `source`, `target`, `fstype` or `root`, e.g. `source:magisk` or `target:/debug_ramdisk/**`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountRule {
+    pub field: MountField,
+    pub pattern: String,
+}
+
+impl MountRule {
+    pub fn parse(value: &str) -> Result<MountRule, String> {
+        let (field, pattern) = value
+            .split_once(':')
+            .ok_or("expected `source:`, `target:`, `fstype:` or `root:` followed by a pattern")?;
+        let field = match field.trim() {
+            "source" => MountField::Source,
+            "target" => MountField::Target,
+            "fstype" => MountField::FsType,
+            "root" => MountField::Root,
+            field => return Err(format!("unknown mount field `{}`", field)),
+        };
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err("empty mount pattern".to_string());
+        }
+        Ok(MountRule { field, pattern: pattern.to_string() })
+    }
+}
+
+/// The two formats the kernel lists mounts in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountTable {
+    /// `/proc/<pid>/mountinfo`: `<id> <parent id> <major:minor> <root> <target> <options>
+    /// [<optional fields>...] - <fstype> <source> <super options>`
+    MountInfo,
+    /// `/proc/mounts` and `/proc/<pid>/mounts`: `<source> <target> <fstype> <options> 0 0`
+    Mounts,
+}
+
+/// Which mount table `path` is, if any.
+pub fn mount_table(path: &str) -> Option<MountTable> {
+    if normalize(path) == "/proc/mounts" {
+        return Some(MountTable::Mounts);
+    }
+    match proc_file_name(path).as_deref() {
+        Some("mountinfo") => Some(MountTable::MountInfo),
+        Some("mounts") => Some(MountTable::Mounts),
+        _ => None,
+    }
+}
+
+/// The fields of a mount entry that rules match against, with the kernel's octal escapes
+/// (`\040` for a space and so on) undone.
+struct Mount<'a> {
+    source: Cow<'a, str>,
+    target: Cow<'a, str>,
+    fstype: Cow<'a, str>,
+    root: Option<Cow<'a, str>>,
+}
+
+/// A `mountinfo` line, split into the mount IDs and everything after them.
+struct MountInfoLine<'a> {
+    id: u32,
+    parent: u32,
+    rest: &'a str,
+    mount: Mount<'a>,
+}
+
+fn parse_mountinfo(line: &str) -> Option<MountInfoLine<'_>> {
+    let mut split = line.splitn(3, ' ');
+    let id = split.next()?.parse().ok()?;
+    let parent = split.next()?.parse().ok()?;
+    let rest = split.next()?;
+
+    let fields: Vec<&str> = rest.trim_end_matches('\n').split(' ').collect();
+    // The optional fields are terminated by a lone `-`
+    let separator = 4 + fields.get(4..)?.iter().position(|f| *f == "-")?;
+    Some(MountInfoLine {
+        id,
+        parent,
+        rest,
+        mount: Mount {
+            source: unescape(fields.get(separator + 2)?),
+            target: unescape(fields.get(2)?),
+            fstype: unescape(fields.get(separator + 1)?),
+            root: Some(unescape(fields.get(1)?)),
+        },
+    })
+}
+
+fn parse_mounts(line: &str) -> Option<Mount<'_>> {
+    let mut fields = line.split(' ');
+    Some(Mount {
+        source: unescape(fields.next()?),
+        target: unescape(fields.next()?),
+        fstype: unescape(fields.next()?),
+        root: None,
+    })
+}
+
+fn unescape(field: &str) -> Cow<'_, str> {
+    if !field.contains('\\') {
+        return Cow::Borrowed(field);
+    }
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).filter(|o| o.iter().all(|b| (b'0'..=b'7').contains(b)));
+        match octal {
+            Some(o) if bytes[i] == b'\\' => {
+                out.push(o.iter().fold(0u8, |n, b| n.wrapping_mul(8) + (b - b'0')));
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Mounts to remove from the mount tables of `/proc`.
+#[derive(Debug, Default)]
+pub struct MountFilter {
+    rules: Vec<MountRule>,
+}
+
+impl MountFilter {
+    pub fn new(rules: &[MountRule]) -> MountFilter {
+        MountFilter { rules: rules.to_vec() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn hides(&self, mount: &Mount) -> bool {
+        self.rules.iter().any(|rule| {
+            let value = match rule.field {
+                MountField::Source => &mount.source,
+                MountField::Target => &mount.target,
+                MountField::FsType => &mount.fstype,
+                MountField::Root => match &mount.root {
+                    Some(root) => root,
+                    None => return false,
+                },
+            };
+            glob::matches(&rule.pattern, value)
+        })
+    }
+
+    /// Filter the contents of a mount table, returning the filtered text and the number of
+    /// mounts removed. Lines that can't be parsed are kept as they are.
+    pub fn apply(&self, table: MountTable, text: &str) -> (String, usize) {
+        match table {
+            MountTable::MountInfo => self.filter_mountinfo(text),
+            MountTable::Mounts => {
+                let mut out = String::with_capacity(text.len());
+                let mut removed = 0;
+                for line in text.split_inclusive('\n') {
+                    match parse_mounts(line) {
+                        Some(mount) if self.hides(&mount) => removed += 1,
+                        _ => out.push_str(line),
+                    }
+                }
+                (out, removed)
+            }
+        }
+    }
+
+    /// Remove hidden mounts from `mountinfo`. The remaining mounts keep their own IDs, which
+    /// the kernel also reports through `statx` and `/proc/self/fdinfo`, and which have gaps
+    /// anyway. A mount whose parent was hidden is moved up to the closest ancestor that wasn't.
+    fn filter_mountinfo(&self, text: &str) -> (String, usize) {
+        let lines: Vec<(&str, Option<MountInfoLine>)> =
+            text.split_inclusive('\n').map(|line| (line, parse_mountinfo(line))).collect();
+
+        let parents: HashMap<u32, u32> = lines.iter().flat_map(|(_, m)| m).map(|m| (m.id, m.parent)).collect();
+        let hidden: HashSet<u32> =
+            lines.iter().flat_map(|(_, m)| m).filter(|m| self.hides(&m.mount)).map(|m| m.id).collect();
+        if hidden.is_empty() {
+            return (text.to_string(), 0);
+        }
+
+        let visible_parent = |mut parent: u32| {
+            // Bounded, in case of a (bogus) cycle
+            for _ in 0..parents.len() {
+                if !hidden.contains(&parent) {
+                    break;
+                }
+                parent = parents[&parent];
+            }
+            parent
+        };
+
+        let mut out = String::with_capacity(text.len());
+        for (line, parsed) in &lines {
+            match parsed {
+                Some(m) if hidden.contains(&m.id) => {}
+                Some(m) => out.push_str(&format!("{} {} {}", m.id, visible_parent(m.parent), m.rest)),
+                None => out.push_str(line),
+            }
+        }
+        (out, hidden.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = include_str!("../testdata/mountinfo");
+    const MOUNTS: &str = include_str!("../testdata/mounts");
+
+    fn filter() -> MountFilter {
+        let rules = ["source:magisk", "source:KSU", "root:/adb/modules/**"];
+        MountFilter::new(&rules.iter().map(|r| MountRule::parse(r).unwrap()).collect::<Vec<_>>())
+    }
+
+    fn ids(text: &str) -> Vec<(u32, u32)> {
+        text.lines().map(|l| parse_mountinfo(l).unwrap()).map(|m| (m.id, m.parent)).collect()
+    }
+
+    #[test]
+    fn mountinfo_entries_are_removed_and_reparented() {
+        let (filtered, removed) = filter().apply(MountTable::MountInfo, MOUNTINFO);
+        assert_eq!(removed, 4);
+        assert!(!filtered.contains("magisk") && !filtered.contains("/adb/modules"));
+
+        // Every other mount keeps its ID, and every parent is a visible mount (or the root's parent)
+        let expected: Vec<u32> =
+            ids(MOUNTINFO).into_iter().map(|(id, _)| id).filter(|id| ![1590, 1591, 1593, 1596].contains(id)).collect();
+        let ids = ids(&filtered);
+        let kept: Vec<u32> = ids.iter().map(|(id, _)| *id).collect();
+        assert_eq!(kept, expected);
+        for (_, parent) in &ids {
+            assert!(*parent == 1584 || kept.contains(parent), "{}", parent);
+        }
+
+        // A mount on top of a hidden one is moved to its closest visible ancestor, /vendor
+        assert!(filtered.contains("\n1592 1585 253:7 / /vendor ro,relatime master:7 - ext4"));
+        assert!(filtered.contains("\n1597 1592 7:40 / /vendor/etc/keep ro,relatime - ext4"));
+        assert!(filtered.ends_with("/apex rw,nosuid,nodev,relatime master:10 - tmpfs tmpfs rw,seclabel,mode=755\n"));
+    }
+
+    #[test]
+    fn mounts_entries_are_removed() {
+        let (filtered, removed) = filter().apply(MountTable::Mounts, MOUNTS);
+        assert_eq!(removed, 4);
+        assert_eq!(filtered.lines().count(), MOUNTS.lines().count() - 4);
+        assert!(!filtered.contains("magisk") && !filtered.contains("KSU"));
+        assert!(filtered.starts_with("/dev/block/dm-6 / ext4 ro,seclabel,relatime 0 0\n"));
+    }
+
+    #[test]
+    fn escaped_fields_are_matched_unescaped() {
+        let rule = MountRule::parse("target:/mnt/media_rw/My Card").unwrap();
+        let (filtered, removed) = MountFilter::new(&[rule]).apply(MountTable::Mounts, MOUNTS);
+        assert_eq!(removed, 1);
+        assert!(!filtered.contains("My\\040Card"));
+    }
+
+    #[test]
+    fn nothing_hidden_changes_nothing() {
+        let filter = MountFilter::new(&[MountRule::parse("fstype:nfs").unwrap()]);
+        assert_eq!(filter.apply(MountTable::MountInfo, MOUNTINFO), (MOUNTINFO.to_string(), 0));
+        assert_eq!(filter.apply(MountTable::Mounts, MOUNTS), (MOUNTS.to_string(), 0));
+    }
+
+    #[test]
+    fn mount_tables() {
+        assert_eq!(mount_table("/proc/self/mountinfo"), Some(MountTable::MountInfo));
+        assert_eq!(mount_table("/proc/1234/task/1240/mountinfo"), Some(MountTable::MountInfo));
+        assert_eq!(mount_table("/proc/mounts"), Some(MountTable::Mounts));
+        assert_eq!(mount_table("/proc/self/mounts"), Some(MountTable::Mounts));
+        assert_eq!(mount_table("/proc/self/mountstats"), None);
+        assert!(MountRule::parse("device:/dev/block/dm-6").is_err());
+        assert!(MountRule::parse("magisk").is_err());
+    }
+}
@@ -0,0 +1,595 @@
+use std::sync::atomic::AtomicPtr;
+use std::sync::OnceLock;
+
+use jni::objects::{GlobalRef, JFieldID, JMethodID, JObject, JString, JValue};
+use jni::signature::{JavaType, Primitive};
+use jni::sys::{jboolean, jfieldID, jint, jmethodID, jobject, JNIEnv as RawJNIEnv, JNI_FALSE};
+use jni::JNIEnv;
+
+use crate::{
+    args::sdk_level,
+    config,
+    events::{self, Action},
+    jni_hooks::{self, call_object, env_from_raw, JniHook},
+    plt::load_orig as orig,
+    ZygiskApi,
+};
+
+static ORIG_TRANSACT: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The interface token every `IPackageManager` transaction starts with.
+const PACKAGE_MANAGER: &str = "android.content.pm.IPackageManager";
+
+/// `IPackageManager` calls whose request names a package, directly or in an intent. Blocked names
+/// are disguised in these, and only these are ever marshalled.
+const REWRITTEN_TRANSACTIONS: &[&str] = &[
+    "TRANSACTION_getPackageInfo",
+    "TRANSACTION_getPackageInfoVersioned",
+    "TRANSACTION_getPackageUid",
+    "TRANSACTION_getPackageGids",
+    "TRANSACTION_getApplicationInfo",
+    "TRANSACTION_getActivityInfo",
+    "TRANSACTION_getServiceInfo",
+    "TRANSACTION_getReceiverInfo",
+    "TRANSACTION_getProviderInfo",
+    "TRANSACTION_getInstallerPackageName",
+    "TRANSACTION_getInstallSourceInfo",
+    "TRANSACTION_getApplicationEnabledSetting",
+    "TRANSACTION_getComponentEnabledSetting",
+    "TRANSACTION_isPackageAvailable",
+    "TRANSACTION_checkPermission",
+    "TRANSACTION_resolveIntent",
+    "TRANSACTION_resolveService",
+    "TRANSACTION_queryIntentActivities",
+    "TRANSACTION_queryIntentServices",
+    "TRANSACTION_queryIntentReceivers",
+    "TRANSACTION_queryIntentContentProviders",
+];
+
+/// `IPackageManager` calls that reply with a `ParceledListSlice` of packages, applications or
+/// components, which have to be filtered rather than refused.
+const LIST_TRANSACTIONS: &[&str] = &[
+    "TRANSACTION_getInstalledPackages",
+    "TRANSACTION_getInstalledApplications",
+    "TRANSACTION_queryIntentActivities",
+    "TRANSACTION_queryIntentServices",
+    "TRANSACTION_queryIntentReceivers",
+    "TRANSACTION_queryIntentContentProviders",
+];
+
+/// Hidden framework members, looked up while still in zygote, where the hidden API checks don't
+/// apply yet. Public APIs are looked up by name when needed.
+struct PackageManagerJni {
+    rewritten_transactions: Vec<jint>,
+    list_transactions: Vec<jint>,
+    /// `None` if `ParceledListSlice` couldn't be resolved, and lists aren't filtered.
+    slice: Option<ListSliceJni>,
+}
+
+struct ListSliceJni {
+    /// `ParceledListSlice.CREATOR`
+    creator: GlobalRef,
+    /// `ParceledListSlice.getList()`
+    get_list: MethodId,
+}
+
+/// The `Parcel` methods the transaction hook may call on any binder call, looked up once.
+struct ParcelJni {
+    data_size: MethodId,
+    data_position: MethodId,
+    set_data_position: MethodId,
+    read_int: MethodId,
+    read_string: MethodId,
+    /// `Parcel.mNativePtr`, the `android::Parcel` behind it
+    native_ptr: FieldId,
+}
+
+struct MethodId(jmethodID);
+struct FieldId(jfieldID);
+
+// Member IDs stay valid for as long as their class is loaded, which for a boot class is forever
+unsafe impl Send for MethodId {}
+unsafe impl Sync for MethodId {}
+unsafe impl Send for FieldId {}
+unsafe impl Sync for FieldId {}
+
+static PACKAGE_MANAGER_JNI: OnceLock<PackageManagerJni> = OnceLock::new();
+static PARCEL_JNI: OnceLock<ParcelJni> = OnceLock::new();
+
+/// Make the profile's blocked packages look uninstalled to `PackageManager`.
+///
+/// All of `PackageManager` ends up in `BinderProxy.transactNative`, so that is where packages are
+/// hidden: requests naming a blocked package have the name replaced by one that can't exist,
+/// so the system server itself answers "not installed" (`getPackageInfo` throws
+/// `NameNotFoundException`, `getLaunchIntentForPackage` returns `null` and so on), and lists of
+/// installed packages or matching components have the blocked packages removed.
+///
+/// Every binder call of the app goes through the hook, so it first tells the few calls it
+/// rewrites apart by their transaction code and interface token, without copying anything.
+/// The hook also refuses the intents `jni_hooks` blocks.
+pub unsafe fn apply(api: &ZygiskApi, env: &mut JNIEnv) {
+    if config::active().profile.blocked_packages.is_empty() {
+        return;
+    }
+
+    let Some(parcel) = resolve_parcel(env) else {
+        let _ = env.exception_clear();
+        return error!("Unable to resolve Parcel, PackageManager calls won't be rewritten nor intents blocked");
+    };
+    let _ = PARCEL_JNI.set(parcel);
+    match resolve(env) {
+        Some(jni) => {
+            if jni.slice.is_none() {
+                warn!("PackageManager lists won't be filtered");
+            }
+            let _ = PACKAGE_MANAGER_JNI.set(jni);
+        }
+        None => {
+            let _ = env.exception_clear();
+            warn!("PackageManager calls won't be rewritten");
+        }
+    }
+
+    jni_hooks::register(
+        api,
+        env,
+        &[JniHook {
+            class: c"android/os/BinderProxy",
+            name: c"transactNative",
+            signature: c"(ILandroid/os/Parcel;Landroid/os/Parcel;I)Z",
+            replacement: hook_transact as *mut _,
+            orig: &ORIG_TRANSACT,
+        }],
+    );
+}
+
+fn resolve(env: &JNIEnv) -> Option<PackageManagerJni> {
+    let stub = "android/content/pm/IPackageManager$Stub";
+    let slice = resolve_slice(env);
+    if slice.is_none() {
+        let _ = env.exception_clear();
+    }
+    Some(PackageManagerJni {
+        rewritten_transactions: transaction_codes(env, stub, REWRITTEN_TRANSACTIONS)?,
+        list_transactions: transaction_codes(env, stub, LIST_TRANSACTIONS)?,
+        slice,
+    })
+}
+
+/// The codes of the `names` transactions of an AIDL stub class, skipping those it doesn't
+/// have, as not every Android version has every call.
+pub fn transaction_codes(env: &JNIEnv, stub: &str, names: &[&str]) -> Option<Vec<jint>> {
+    let stub = env.find_class(stub).ok()?;
+    let codes = names
+        .iter()
+        .filter_map(|name| match env.get_static_field(stub, *name, "I").and_then(|v| v.i()) {
+            Ok(code) => Some(code),
+            Err(_) => {
+                let _ = env.exception_clear();
+                None
+            }
+        })
+        .collect();
+    Some(codes)
+}
+
+fn resolve_slice(env: &JNIEnv) -> Option<ListSliceJni> {
+    let slice = env.find_class("android/content/pm/ParceledListSlice").ok()?;
+    let creator = env
+        .get_static_field(slice, "CREATOR", "Landroid/os/Parcelable$ClassLoaderCreator;")
+        .and_then(|v| v.l())
+        .ok()?;
+    Some(ListSliceJni {
+        creator: env.new_global_ref(creator).ok()?,
+        get_list: MethodId(env.get_method_id(slice, "getList", "()Ljava/util/List;").ok()?.into_inner()),
+    })
+}
+
+fn resolve_parcel(env: &JNIEnv) -> Option<ParcelJni> {
+    let parcel = env.find_class("android/os/Parcel").ok()?;
+    let method = |name, sig| env.get_method_id(parcel, name, sig).ok().map(|id| MethodId(id.into_inner()));
+    Some(ParcelJni {
+        data_size: method("dataSize", "()I")?,
+        data_position: method("dataPosition", "()I")?,
+        set_data_position: method("setDataPosition", "(I)V")?,
+        read_int: method("readInt", "()I")?,
+        read_string: method("readString", "()Ljava/lang/String;")?,
+        native_ptr: FieldId(env.get_field_id(parcel, "mNativePtr", "J").ok()?.into_inner()),
+    })
+}
+
+/// Where the interface token starts in a transaction's data, after the strict mode policy, the
+/// work source (Android 10 and later) and the `SYST` header (Android 11 and later).
+pub fn token_offset(sdk: i32) -> i32 {
+    match sdk {
+        ..=28 => 4,
+        29 => 8,
+        _ => 12,
+    }
+}
+
+/// The interface token of a transaction, if it is one of `tokens`.
+///
+/// Only the token's length is read unless it matches one of them, so telling any other call
+/// apart costs a handful of JNI calls and no copy. The parcel's position is left as it was.
+pub fn interface_token(env: &JNIEnv, parcel: jobject, tokens: &[&'static str]) -> Option<&'static str> {
+    let jni = PARCEL_JNI.get()?;
+    if parcel.is_null() {
+        return None;
+    }
+    let parcel = JObject::from(parcel);
+    match read_token(env, jni, parcel, tokens) {
+        Ok(token) => token,
+        Err(_) => {
+            let _ = env.exception_clear();
+            None
+        }
+    }
+}
+
+fn read_token(
+    env: &JNIEnv,
+    jni: &ParcelJni,
+    parcel: JObject,
+    tokens: &[&'static str],
+) -> jni::errors::Result<Option<&'static str>> {
+    let int = |method: &MethodId| {
+        env.call_method_unchecked(parcel, JMethodID::from(method.0), JavaType::Primitive(Primitive::Int), &[])?.i()
+    };
+    let set_position = |position: jint| {
+        let void = JavaType::Primitive(Primitive::Void);
+        let args = [JValue::Int(position)];
+        env.call_method_unchecked(parcel, JMethodID::from(jni.set_data_position.0), void, &args).map(|_| ())
+    };
+
+    let offset = token_offset(sdk_level());
+    if int(&jni.data_size)? < offset + 4 {
+        return Ok(None);
+    }
+    let saved = int(&jni.data_position)?;
+    set_position(offset)?;
+    let len = int(&jni.read_int)?;
+    let mut candidates = tokens.iter().filter(|t| t.encode_utf16().count() as jint == len).peekable();
+
+    let mut token = None;
+    if candidates.peek().is_some() {
+        set_position(offset)?;
+        let string = JavaType::Object("java/lang/String".to_string());
+        let string = env.call_method_unchecked(parcel, JMethodID::from(jni.read_string.0), string, &[])?.l()?;
+        if !string.is_null() {
+            let string: String = env.get_string(JString::from(string))?.into();
+            token = candidates.find(|t| **t == string).copied();
+        }
+    }
+    set_position(saved)?;
+    Ok(token)
+}
+
+/// The raw contents of a parcel, binders and file descriptors included, which `Parcel.marshall`
+/// refuses to copy.
+///
+/// They are read from the `android::Parcel` behind it, which has started with
+/// `status_t mError; uint8_t* mData; size_t mDataSize;` in every Android version. The size found
+/// there has to match `dataSize()` for the data to be trusted.
+pub fn parcel_data(env: &JNIEnv, parcel: jobject) -> Option<Vec<u8>> {
+    let jni = PARCEL_JNI.get()?;
+    if parcel.is_null() {
+        return None;
+    }
+    let parcel = JObject::from(parcel);
+    let native = env
+        .get_field_unchecked(parcel, JFieldID::from(jni.native_ptr.0), JavaType::Primitive(Primitive::Long))
+        .and_then(|v| v.j());
+    let size = env
+        .call_method_unchecked(parcel, JMethodID::from(jni.data_size.0), JavaType::Primitive(Primitive::Int), &[])
+        .and_then(|v| v.i());
+    let (Ok(native), Ok(size)) = (native, size) else {
+        let _ = env.exception_clear();
+        return None;
+    };
+    if native == 0 {
+        return None;
+    }
+    unsafe {
+        let fields = native as usize as *const usize;
+        let data = *fields.add(1) as *const u8;
+        let data_size = *fields.add(2);
+        if data.is_null() || data_size != usize::try_from(size).ok()? {
+            return None;
+        }
+        Some(std::slice::from_raw_parts(data, data_size).to_vec())
+    }
+}
+
+/// Where a string written by `Parcel.writeString` (UTF-16) or `Parcel.writeString8` (UTF-8)
+/// lies in marshalled parcel data, as ranges of the string contents.
+///
+/// Both are a 32-bit length followed by the characters, starting at a 4 byte boundary.
+fn find_string(data: &[u8], s: &str) -> Vec<std::ops::Range<usize>> {
+    let utf16: Vec<u8> = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let encodings = [(s.encode_utf16().count(), utf16.as_slice()), (s.len(), s.as_bytes())];
+
+    let mut found = Vec::new();
+    for offset in (0..data.len()).step_by(4) {
+        let Some(len) = data.get(offset..offset + 4) else { break };
+        let len = i32::from_le_bytes(len.try_into().unwrap());
+        for (chars, encoded) in encodings {
+            let start = offset + 4;
+            if len as usize == chars && data[start..].starts_with(encoded) {
+                found.push(start..start + encoded.len());
+            }
+        }
+    }
+    found
+}
+
+/// Overwrite every occurrence of the blocked packages with a name of the same length that is
+/// not a valid package name, returning whether there were any.
+fn disguise_packages(data: &mut [u8], packages: &[String]) -> bool {
+    let mut changed = false;
+    for package in packages {
+        for range in find_string(data, package) {
+            let utf16 = range.len() != package.len();
+            for (i, byte) in data[range].iter_mut().enumerate() {
+                *byte = if utf16 && i % 2 == 1 { 0 } else { b'-' };
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+type TransactFn = extern "C" fn(*mut RawJNIEnv, jobject, jint, jobject, jobject, jint) -> jboolean;
+
+extern "C" fn hook_transact(
+    env: *mut RawJNIEnv,
+    this: jobject,
+    code: jint,
+    data: jobject,
+    reply: jobject,
+    flags: jint,
+) -> jboolean {
+    let Some(orig_fn) = orig::<TransactFn>(&ORIG_TRANSACT) else {
+        return JNI_FALSE;
+    };
+    let Some(jni_env) = env_from_raw(env) else {
+        return orig_fn(env, this, code, data, reply, flags);
+    };
+
+    if let Some(ret) = jni_hooks::refuse_blocked_intent(&jni_env, code, data, reply, flags) {
+        return ret;
+    }
+
+    // Most calls are told apart by their code alone
+    let Some(jni) = PACKAGE_MANAGER_JNI.get() else {
+        return orig_fn(env, this, code, data, reply, flags);
+    };
+    let rewritten = jni.rewritten_transactions.contains(&code);
+    let listed = jni.list_transactions.contains(&code) && jni.slice.is_some();
+    if !(rewritten || listed) || interface_token(&jni_env, data, &[PACKAGE_MANAGER]).is_none() {
+        return orig_fn(env, this, code, data, reply, flags);
+    }
+
+    let blocked = &config::active().profile.blocked_packages;
+    if rewritten {
+        if let Some(mut bytes) = marshall(&jni_env, data) {
+            if disguise_packages(&mut bytes, blocked) {
+                info!("GeoInk-Core: Hid a blocked package from PackageManager call {}", code);
+                events::record("transactNative", Action::Hide, format!("PackageManager call {}", code));
+                unmarshall(&jni_env, data, &bytes);
+            }
+        }
+    }
+
+    let ret = orig_fn(env, this, code, data, reply, flags);
+
+    if ret != JNI_FALSE && listed && !reply.is_null() && !jni_env.exception_check().unwrap_or(true) {
+        if let Some(slice) = &jni.slice {
+            filter_list_reply(&jni_env, slice, JObject::from(reply), blocked);
+        }
+    }
+    ret
+}
+
+/// The raw contents of a parcel, or `None` if it holds binders or file descriptors, which no
+/// request about a package name does.
+fn marshall(env: &JNIEnv, parcel: jobject) -> Option<Vec<u8>> {
+    if parcel.is_null() {
+        return None;
+    }
+    let bytes = call_object(env, JObject::from(parcel), "marshall", "()[B").filter(|b| !b.is_null())?;
+    env.convert_byte_array(bytes.into_inner()).ok()
+}
+
+fn unmarshall(env: &JNIEnv, parcel: jobject, bytes: &[u8]) {
+    let Ok(array) = env.byte_array_from_slice(bytes) else { return };
+    let args = [JValue::Object(JObject::from(array)), JValue::Int(0), JValue::Int(bytes.len() as jint)];
+    if env.call_method(JObject::from(parcel), "unmarshall", "([BII)V", &args).is_err() {
+        let _ = env.exception_clear();
+    }
+}
+
+/// Read the `ParceledListSlice` a list call replied with, drop the blocked packages and write
+/// it back. The reply is left alone if nothing was dropped, or if anything goes wrong.
+fn filter_list_reply(env: &JNIEnv, jni: &ListSliceJni, reply: JObject, blocked: &[String]) {
+    if rewrite_list_reply(env, jni, reply, blocked).is_err() {
+        let _ = env.exception_clear();
+    }
+    let _ = env.call_method(reply, "setDataPosition", "(I)V", &[JValue::Int(0)]);
+}
+
+fn rewrite_list_reply(
+    env: &JNIEnv,
+    jni: &ListSliceJni,
+    reply: JObject,
+    blocked: &[String],
+) -> jni::errors::Result<()> {
+    env.call_method(reply, "setDataPosition", "(I)V", &[JValue::Int(0)])?;
+    env.call_method(reply, "readException", "()V", &[])?;
+    if env.call_method(reply, "readInt", "()I", &[])?.i()? == 0 {
+        // A null list
+        return Ok(());
+    }
+    let slice = env
+        .call_method(
+            jni.creator.as_obj(),
+            "createFromParcel",
+            "(Landroid/os/Parcel;)Ljava/lang/Object;",
+            &[JValue::Object(reply)],
+        )?
+        .l()?;
+    let list = env
+        .call_method_unchecked(
+            slice,
+            JMethodID::from(jni.get_list.0),
+            JavaType::Object("java/util/List".to_string()),
+            &[],
+        )?
+        .l()?;
+
+    let mut removed = 0;
+    let items = env.call_method(list, "iterator", "()Ljava/util/Iterator;", &[])?.l()?;
+    while env.call_method(items, "hasNext", "()Z", &[])?.z()? {
+        let item = env.call_method(items, "next", "()Ljava/lang/Object;", &[])?.l()?;
+        if package_of(env, item)?.is_some_and(|package| blocked.contains(&package)) {
+            env.call_method(items, "remove", "()V", &[])?;
+            removed += 1;
+        }
+        env.delete_local_ref(item)?;
+    }
+    if removed == 0 {
+        return Ok(());
+    }
+
+    info!("GeoInk-Core: Hid {} blocked entries from PackageManager", removed);
+    events::record("transactNative", Action::Filter, format!("{} PackageManager list entries", removed));
+    env.call_method(reply, "setDataSize", "(I)V", &[JValue::Int(0)])?;
+    env.call_method(reply, "writeNoException", "()V", &[])?;
+    env.call_method(reply, "writeInt", "(I)V", &[JValue::Int(1)])?;
+    env.call_method(slice, "writeToParcel", "(Landroid/os/Parcel;I)V", &[JValue::Object(reply), JValue::Int(0)])?;
+    Ok(())
+}
+
+/// The package a list entry belongs to: a `PackageInfo`, an `ApplicationInfo` or other
+/// `PackageItemInfo`, or a `ResolveInfo`.
+fn package_of(env: &JNIEnv, item: JObject) -> jni::errors::Result<Option<String>> {
+    if item.is_null() {
+        return Ok(None);
+    }
+    if env.is_instance_of(item, "android/content/pm/ResolveInfo")? {
+        for (field, class) in [
+            ("activityInfo", "Landroid/content/pm/ActivityInfo;"),
+            ("serviceInfo", "Landroid/content/pm/ServiceInfo;"),
+            ("providerInfo", "Landroid/content/pm/ProviderInfo;"),
+        ] {
+            let info = env.get_field(item, field, class)?.l()?;
+            if !info.is_null() {
+                return package_field(env, info);
+            }
+        }
+        return Ok(None);
+    }
+    if env.is_instance_of(item, "android/content/pm/PackageInfo")?
+        || env.is_instance_of(item, "android/content/pm/PackageItemInfo")?
+    {
+        return package_field(env, item);
+    }
+    Ok(None)
+}
+
+fn package_field(env: &JNIEnv, info: JObject) -> jni::errors::Result<Option<String>> {
+    let package = env.get_field(info, "packageName", "Ljava/lang/String;")?.l()?;
+    if package.is_null() {
+        return Ok(None);
+    }
+    Ok(Some(env.get_string(JString::from(package))?.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A parcel as `Parcel.writeString` leaves it.
+    fn string16(out: &mut Vec<u8>, s: &str) {
+        let units: Vec<u16> = s.encode_utf16().collect();
+        out.extend((units.len() as i32).to_le_bytes());
+        out.extend(units.iter().flat_map(|u| u.to_le_bytes()));
+        out.extend([0, 0]);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    /// A parcel as `Parcel.writeString8` leaves it.
+    fn string8(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as i32).to_le_bytes());
+        out.extend(s.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    /// `getPackageInfo(String packageName, long flags, int userId)`
+    fn get_package_info(package: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(0i32.to_le_bytes()); // strict mode policy
+        string16(&mut data, PACKAGE_MANAGER);
+        string16(&mut data, package);
+        data.extend(0i64.to_le_bytes());
+        data.extend(0i32.to_le_bytes());
+        data
+    }
+
+    /// What `Parcel.writeInterfaceToken` writes before the token itself.
+    fn token_header(sdk: i32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(0x4000_0000i32.to_le_bytes()); // strict mode policy
+        if sdk >= 29 {
+            data.extend((-1i32).to_le_bytes()); // work source
+        }
+        if sdk >= 30 {
+            data.extend(*b"TSYS"); // 'SYST', as an int
+        }
+        data
+    }
+
+    #[test]
+    fn interface_tokens_are_found_after_the_header() {
+        for sdk in [26, 28, 29, 30, 34] {
+            let mut data = token_header(sdk);
+            string16(&mut data, PACKAGE_MANAGER);
+            let len_at = token_offset(sdk) as usize;
+            let len = i32::from_le_bytes(data[len_at..len_at + 4].try_into().unwrap());
+            assert_eq!(len as usize, PACKAGE_MANAGER.len(), "SDK {}", sdk);
+            assert_eq!(find_string(&data, PACKAGE_MANAGER).first().map(|r| r.start), Some(len_at + 4));
+        }
+    }
+
+    #[test]
+    fn blocked_packages_are_disguised() {
+        let blocked = ["com.sukisu.ultra".to_string()];
+
+        let mut data = get_package_info("com.sukisu.ultra");
+        let len = data.len();
+        assert!(disguise_packages(&mut data, &blocked));
+        assert_eq!(data.len(), len);
+        assert!(find_string(&data, "com.sukisu.ultra").is_empty());
+        assert_eq!(find_string(&data, &"-".repeat(16)).len(), 1);
+        assert_eq!(find_string(&data, PACKAGE_MANAGER).len(), 1);
+
+        // Intents use UTF-8 strings for their package since Android 12
+        let mut data = Vec::new();
+        string16(&mut data, PACKAGE_MANAGER);
+        string8(&mut data, "com.sukisu.ultra");
+        assert!(disguise_packages(&mut data, &blocked));
+        assert_eq!(find_string(&data, &"-".repeat(16)).len(), 1);
+    }
+
+    #[test]
+    fn only_whole_names_are_disguised() {
+        let blocked = ["com.sukisu.ultra".to_string()];
+        for package in ["com.sukisu.ultra.extra", "com.sukisu", "org.com.sukisu.ultra"] {
+            let mut data = get_package_info(package);
+            let original = data.clone();
+            assert!(!disguise_packages(&mut data, &blocked), "{}", package);
+            assert_eq!(data, original);
+        }
+    }
+}
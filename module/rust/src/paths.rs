@@ -0,0 +1,347 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use libc::c_int;
+
+use crate::{errno, glob};
+
+/// How a [PathRule] pattern is compared against a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathMatch {
+    /// The path itself, but nothing below it.
+    Exact,
+    /// The path and everything below it. Matching is per component, so `/sdcard/Fox` does not
+    /// cover `/sdcard/Foxit`.
+    Prefix,
+    /// A [glob](crate::glob::matches) over the full path.
+    Glob,
+    /// Any path with a component of this name, and everything below it.
+    Basename,
+}
+
+/// A single path hiding (or allow-listing) rule, written in the config as `<kind>:<pattern>`.
+///
+/// A pattern without a kind is a prefix rule, e.g. `hide = /system/addon.d`.
+///
+/// Hidden paths fail with `ENOENT` by default, both for the path itself and for anything looked
+/// up below it, just like a directory that does not exist. Either can be overridden with
+/// trailing `errno=<name>` and `child_errno=<name>` options, e.g. `child_errno=ENOTDIR` to make a
+/// hidden entry pose as a regular file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathRule {
+    pub kind: PathMatch,
+    pub pattern: String,
+    /// `errno` reported for the matched path itself.
+    pub errno: c_int,
+    /// `errno` reported for paths below the matched one.
+    pub child_errno: c_int,
+}
+
+impl PathRule {
+    pub fn parse(value: &str) -> Result<PathRule, String> {
+        let mut value = value.trim();
+        let (mut errno, mut child_errno) = (libc::ENOENT, libc::ENOENT);
+        while let Some((rest, option)) = value.rsplit_once(char::is_whitespace) {
+            let slot = match option.split_once('=') {
+                Some(("errno", _)) => &mut errno,
+                Some(("child_errno", _)) => &mut child_errno,
+                _ => break,
+            };
+            let name = option.split_once('=').map(|(_, n)| n).unwrap_or_default();
+            *slot = errno::from_name(name).ok_or_else(|| format!("unsupported errno `{}`", name))?;
+            value = rest.trim_end();
+        }
+
+        let (kind, pattern) = match value.split_once(':') {
+            Some(("exact", p)) => (PathMatch::Exact, p),
+            Some(("prefix", p)) => (PathMatch::Prefix, p),
+            Some(("glob", p)) => (PathMatch::Glob, p),
+            Some(("name", p)) => (PathMatch::Basename, p),
+            Some((kind, _)) if !kind.starts_with('/') => {
+                return Err(format!("unknown path rule kind `{}`", kind))
+            }
+            _ => (PathMatch::Prefix, value),
+        };
+        let pattern = pattern.trim();
+
+        match kind {
+            PathMatch::Basename if pattern.is_empty() || pattern.contains('/') => {
+                Err(format!("`{}` is not a file name", pattern))
+            }
+            PathMatch::Basename => Ok(pattern.to_string()),
+            _ if !pattern.starts_with('/') => Err(format!("`{}` is not an absolute path", pattern)),
+            _ => Ok(normalize(pattern).into_owned()),
+        }
+        .map(|pattern| PathRule { kind, pattern, errno, child_errno })
+    }
+}
+
+impl fmt::Display for PathRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            PathMatch::Exact => "exact",
+            PathMatch::Prefix => "prefix",
+            PathMatch::Glob => "glob",
+            PathMatch::Basename => "name",
+        };
+        write!(f, "{}:{}", kind, self.pattern)?;
+        if self.errno != libc::ENOENT {
+            write!(f, " errno={}", errno::name(self.errno))?;
+        }
+        if self.child_errno != libc::ENOENT {
+            write!(f, " child_errno={}", errno::name(self.child_errno))?;
+        }
+        Ok(())
+    }
+}
+
+/// A set of [PathRule]s indexed for lookups, so the file hooks only pay for a few hash lookups
+/// per path component instead of a scan over every rule.
+///
+/// The set is built once when a profile is installed and never mutated afterwards, which lets
+/// every hook query it concurrently without locking.
+#[derive(Debug, Default)]
+pub struct PathSet {
+    exact: HashMap<String, usize>,
+    prefix: HashMap<String, usize>,
+    basename: HashMap<String, usize>,
+    glob: Vec<(String, usize)>,
+    /// `(errno, child_errno)` of each rule.
+    errnos: Vec<(c_int, c_int)>,
+    /// The last component of every non-glob rule.
+    names: HashSet<String>,
+}
+
+/// A rule covering a path, either the path itself or one of its ancestors.
+#[derive(Clone, Copy)]
+struct Hit {
+    rule: usize,
+    on_path: bool,
+}
+
+impl PathSet {
+    pub fn new(rules: &[PathRule]) -> PathSet {
+        let mut set = PathSet::default();
+        for (idx, rule) in rules.iter().enumerate() {
+            set.errnos.push((rule.errno, rule.child_errno));
+            if rule.kind != PathMatch::Glob {
+                set.names.insert(basename(&rule.pattern).to_string());
+            }
+            let pattern = rule.pattern.clone();
+            match rule.kind {
+                PathMatch::Exact => set.exact.entry(pattern).or_insert(idx),
+                PathMatch::Prefix => set.prefix.entry(pattern).or_insert(idx),
+                PathMatch::Basename => set.basename.entry(pattern).or_insert(idx),
+                PathMatch::Glob => {
+                    set.glob.push((pattern, idx));
+                    continue;
+                }
+            };
+        }
+        set
+    }
+
+    /// Find the first rule (in the order given to [Self::new]) covering a normalized path.
+    ///
+    /// Relative paths are only checked against basename rules, since the working directory
+    /// they are relative to is unknown here.
+    fn lookup(&self, path: &str) -> Option<Hit> {
+        let hit = |rule: Option<&usize>, on_path| rule.map(|&rule| Hit { rule, on_path });
+
+        if !path.starts_with('/') {
+            let mut found = None;
+            for (depth, component) in path.rsplit('/').enumerate() {
+                found = earliest(found, hit(self.basename.get(component), depth == 0));
+            }
+            return found;
+        }
+
+        let mut found = hit(self.exact.get(path), true);
+        for ancestor in ancestors(path) {
+            let on_path = ancestor.len() == path.len();
+            found = earliest(found, hit(self.prefix.get(ancestor), on_path));
+            found = earliest(found, hit(self.basename.get(basename(ancestor)), on_path));
+        }
+        for (pattern, idx) in &self.glob {
+            if glob::matches(pattern, path) {
+                found = earliest(found, Some(Hit { rule: *idx, on_path: true }));
+            }
+        }
+        found
+    }
+}
+
+fn earliest(a: Option<Hit>, b: Option<Hit>) -> Option<Hit> {
+    match (a, b) {
+        (Some(a), Some(b)) if b.rule < a.rule => Some(b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The compiled hiding rules of a profile: hide rules minus allow-list exceptions.
+#[derive(Debug, Default)]
+pub struct PathRules {
+    hide: PathSet,
+    allow: PathSet,
+}
+
+impl PathRules {
+    pub fn new(hide: &[PathRule], allow: &[PathRule]) -> PathRules {
+        PathRules { hide: PathSet::new(hide), allow: PathSet::new(allow) }
+    }
+
+    /// If `path` must be hidden from the target process, the `errno` to fail with.
+    pub fn hidden_errno(&self, path: &str) -> Option<c_int> {
+        let path = normalize(path);
+        let hit = self.hide.lookup(&path)?;
+        if self.allow.lookup(&path).is_some() {
+            return None;
+        }
+        let (errno, child_errno) = self.hide.errnos[hit.rule];
+        Some(if hit.on_path { errno } else { child_errno })
+    }
+
+    pub fn is_hidden(&self, path: &str) -> bool {
+        self.hidden_errno(path).is_some()
+    }
+
+    /// Whether a directory entry called `name` could be hidden at all, so directory listings
+    /// only pay for a full lookup on the few entries that might match.
+    pub fn may_hide_name(&self, name: &str) -> bool {
+        !self.hide.glob.is_empty() || self.hide.names.contains(name)
+    }
+}
+
+/// Lexically normalize a path: collapse repeated `/`, drop `.` components and trailing `/`,
+/// and resolve `..` against the preceding component.
+///
+/// Detection code likes to probe `/system//addon.d` or `/system/bin/../addon.d/` to get past
+/// naive string checks, so every path is normalized before it is matched.
+pub fn normalize(path: &str) -> Cow<'_, str> {
+    let absolute = path.starts_with('/');
+    let is_clean = !path.contains("//")
+        && !path.split('/').any(|c| c == "." || c == "..")
+        && (path.len() <= 1 || !path.ends_with('/'));
+    if is_clean {
+        return Cow::Borrowed(path);
+    }
+
+    let mut parts: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if parts.last().is_some_and(|p| *p != "..") => {
+                parts.pop();
+            }
+            // `/..` is `/`, but a relative path may legitimately start with `..`
+            ".." if absolute => {}
+            _ => parts.push(component),
+        }
+    }
+
+    let joined = parts.join("/");
+    Cow::Owned(if absolute { format!("/{}", joined) } else { joined })
+}
+
+/// The name of a per-process `/proc` file, e.g. `maps` for `/proc/self/maps`, `/proc/1234/maps`,
+/// `/proc/thread-self/maps` or `/proc/1234/task/1240/maps`.
+pub fn proc_file_name(path: &str) -> Option<String> {
+    let path = normalize(path);
+    let rest = path.strip_prefix("/proc/")?;
+    let is_id = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    let parts: Vec<&str> = rest.split('/').collect();
+    let name = match parts[..] {
+        [process, name] if process == "self" || process == "thread-self" || is_id(process) => name,
+        [process, "task", tid, name] if (process == "self" || is_id(process)) && is_id(tid) => name,
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+/// Iterate over a normalized absolute path and all of its ancestors, longest first, excluding `/`.
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(path).filter(|p| p.len() > 1);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        rest = current.rfind('/').map(|i| &current[..i]).filter(|p| !p.is_empty());
+        Some(current)
+    })
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(hide: &[&str], allow: &[&str]) -> PathRules {
+        let parse = |v: &[&str]| v.iter().map(|r| PathRule::parse(r).unwrap()).collect::<Vec<_>>();
+        PathRules::new(&parse(hide), &parse(allow))
+    }
+
+    #[test]
+    fn prefix_rules_match_per_component() {
+        let rules = rules(&["/sdcard/Fox"], &[]);
+        assert!(rules.hidden_errno("/sdcard/Fox").is_some());
+        assert!(rules.hidden_errno("/sdcard/Fox/logs/recovery.log").is_some());
+        assert!(rules.hidden_errno("/sdcard/Foxit").is_none());
+        assert!(rules.hidden_errno("/sdcard").is_none());
+    }
+
+    #[test]
+    fn exact_rules_do_not_cover_children() {
+        let rules = rules(&["exact:/system/bin/su"], &[]);
+        assert!(rules.hidden_errno("/system/bin/su").is_some());
+        assert!(rules.hidden_errno("/system/bin/su/x").is_none());
+        assert!(rules.hidden_errno("/system/bin").is_none());
+    }
+
+    #[test]
+    fn glob_and_basename_rules() {
+        let rules = rules(&["glob:/data/local/tmp/*magisk*", "name:su"], &[]);
+        assert!(rules.hidden_errno("/data/local/tmp/magisk.db").is_some());
+        assert!(rules.hidden_errno("/data/local/tmp/x/magisk.db").is_none());
+        assert!(rules.hidden_errno("/system/xbin/su").is_some());
+        assert!(rules.hidden_errno("/sbin/su/bin/sh").is_some());
+        assert!(rules.hidden_errno("su").is_some());
+        assert!(rules.hidden_errno("/system/xbin/sudo").is_none());
+    }
+
+    #[test]
+    fn allow_rules_punch_holes() {
+        let rules = rules(&["/system/addon.d"], &["exact:/system/addon.d/50-keep.sh"]);
+        assert!(rules.hidden_errno("/system/addon.d/51-other.sh").is_some());
+        assert!(rules.hidden_errno("/system/addon.d/50-keep.sh").is_none());
+    }
+
+    #[test]
+    fn paths_are_normalized_before_matching() {
+        let rules = rules(&["/system/addon.d"], &[]);
+        assert!(rules.hidden_errno("/system//addon.d/").is_some());
+        assert!(rules.hidden_errno("/system/./addon.d").is_some());
+        assert!(rules.hidden_errno("/system/bin/../addon.d").is_some());
+        assert!(rules.hidden_errno("/../system/addon.d").is_some());
+        assert_eq!(normalize("../a/./b/"), "../a/b");
+        assert_eq!(normalize("/"), "/");
+    }
+
+    #[test]
+    fn rule_parsing() {
+        let rule = PathRule::parse("/system/addon.d").unwrap();
+        assert_eq!((rule.kind, rule.pattern.as_str()), (PathMatch::Prefix, "/system/addon.d"));
+        assert_eq!((rule.errno, rule.child_errno), (libc::ENOENT, libc::ENOENT));
+
+        let rule = PathRule::parse("name:su child_errno=ENOTDIR").unwrap();
+        assert_eq!((rule.kind, rule.pattern.as_str()), (PathMatch::Basename, "su"));
+        assert_eq!(rule.child_errno, libc::ENOTDIR);
+        assert_eq!(rule.to_string(), "name:su child_errno=ENOTDIR");
+        assert!(PathRule::parse("/x errno=EWHATEVER").is_err());
+        assert!(PathRule::parse("name:a/b").is_err());
+        assert!(PathRule::parse("exact:relative").is_err());
+        assert!(PathRule::parse("regex:/x").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::{api, ZygiskApi};
+
+/// ELFs whose PLT entries we hook: all of them. A PLT hook only catches the calls made from
+/// the ELF it is registered for, and every library calls libc through its own PLT, so hooking
+/// only some would let the others (an app's own library, or `libjavacore` behind `File`) see
+/// what the hooks hide.
+///
+/// Only ELFs mapped when the hooks are committed are covered. Libraries an app loads later are
+/// out of reach, as the Zygisk API is gone by then.
+pub const HOOK_TARGET: &CStr = c".*";
+
+/// A PLT hook: a replacement for `symbol`, and where to keep the original function.
+pub struct PltHook {
+    pub symbol: &'static CStr,
+    pub replacement: *mut (),
+    pub orig: &'static AtomicPtr<()>,
+}
+
+impl PltHook {
+    /// Register the hook for ELFs matching [HOOK_TARGET]. Use [install] instead, which keeps
+    /// the hook out of our own ELF.
+    ///
+    /// Zygisk only writes the original function pointer to `orig` once the hooks are committed
+    /// with [ZygiskApi::plt_hook_commit], which is why it has to be a `'static`.
+    ///
+    /// ## Safety
+    ///
+    /// `replacement` must have the same signature as `symbol`.
+    pub unsafe fn register(&self, api: &ZygiskApi) {
+        api.plt_hook_register(HOOK_TARGET, self.symbol, self.replacement, Some(&mut *self.orig.as_ptr()));
+    }
+
+    /// The original function, if the hook has been committed.
+    pub fn orig(&self) -> Option<*mut ()> {
+        Some(self.orig.load(Ordering::Relaxed)).filter(|p| !p.is_null())
+    }
+}
+
+/// Register `hooks` for every ELF but our own, and commit them.
+///
+/// Our own ELF must be left out, or the hooks would see the module's own calls: the reads of
+/// `/proc/self/maps` that filter it would go through the `open` hook and recurse. Nothing is
+/// hooked if it can't be found. Returns `false` if the hooks weren't installed.
+///
+/// ## Safety
+///
+/// Each `replacement` must have the same signature as its `symbol`.
+pub unsafe fn install(api: &ZygiskApi, hooks: &[PltHook]) -> bool {
+    let Some((own_dev, own_inode, own_path)) = api::mapped_file_at(install as *const () as usize) else {
+        error!("Unable to find our own ELF, not installing PLT hooks");
+        return false;
+    };
+
+    if api.api_version() >= 4 {
+        // API v4 has no exclusions, so leave our own ELF out of the registration instead
+        let Some(elfs) = api::mapped_elfs(HOOK_TARGET) else {
+            error!("Unable to read the memory map, not installing PLT hooks");
+            return false;
+        };
+        for (dev, inode) in elfs.into_iter().filter(|elf| *elf != (own_dev, own_inode)) {
+            for hook in hooks {
+                api.plt_hook_register_inode(dev, inode, hook.symbol, hook.replacement, Some(&mut *hook.orig.as_ptr()));
+            }
+        }
+    } else {
+        let Ok(own) = CString::new(format!("^{}$", regex_lite::escape(&own_path))) else { return false };
+        for hook in hooks {
+            hook.register(api);
+        }
+        api.plt_hook_exclude(&own, None);
+    }
+    api.plt_hook_commit()
+}
+
+/// Load a saved original function pointer as the function type `F`, or `None` if the hook
+/// was never installed.
+pub fn load_orig<F: Copy>(orig: &AtomicPtr<()>) -> Option<F> {
+    let orig_ptr = orig.load(Ordering::Relaxed);
+    if orig_ptr.is_null() {
+        return None;
+    }
+    Some(unsafe { std::mem::transmute_copy::<*mut (), F>(&orig_ptr) })
+}
@@ -0,0 +1,70 @@
+use crate::{
+    build_fields::BuildField, glob, logging::LogBackend, mounts::MountRule, paths::PathRule, props::PropRule,
+    RuntimeFlags,
+};
+
+/// The hiding treatment for one package (or package glob).
+///
+/// A pattern without a `:` matches the package part of a process name, so `com.example.bank`
+/// covers both `com.example.bank` and `com.example.bank:remote`. A pattern with a `:` matches
+/// the full process name, e.g. `com.example.game:anticheat`. Both halves may use globs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub pattern: String,
+    /// Paths that are reported as nonexistent.
+    pub hidden_paths: Vec<PathRule>,
+    /// Exceptions to `hidden_paths`.
+    pub allowed_paths: Vec<PathRule>,
+    /// Regexes for lines to remove from `/proc/<pid>/maps` and `smaps`.
+    pub hidden_maps: Vec<String>,
+    /// Mounts to remove from `/proc/<pid>/mountinfo` and `mounts`.
+    pub hidden_mounts: Vec<MountRule>,
+    /// Property rules, in order of precedence.
+    pub props: Vec<PropRule>,
+    /// `android.os.Build` fields to overwrite.
+    pub build_fields: Vec<BuildField>,
+    /// Zygote runtime flags to remove before the process is specialized.
+    pub cleared_runtime_flags: Vec<RuntimeFlags>,
+    /// Packages that must not be reachable through intents, or visible to `PackageManager`.
+    pub blocked_packages: Vec<String>,
+    /// Where the module logs in the process, [LogBackend::DEFAULT] if unset.
+    pub log: Option<LogBackend>,
+}
+
+impl Profile {
+    pub fn new(pattern: &str) -> Profile {
+        Profile { pattern: pattern.to_string(), ..Default::default() }
+    }
+
+    pub fn matches(&self, process_name: &str) -> bool {
+        if self.pattern.contains(':') {
+            return glob::matches(&self.pattern, process_name);
+        }
+        let package = process_name.split(':').next().unwrap_or_default();
+        glob::matches(&self.pattern, package)
+    }
+
+    /// Append all rules of `other` to this profile.
+    pub fn merge(&mut self, other: &Profile) {
+        self.hidden_paths.extend_from_slice(&other.hidden_paths);
+        self.allowed_paths.extend_from_slice(&other.allowed_paths);
+        self.hidden_maps.extend_from_slice(&other.hidden_maps);
+        self.hidden_mounts.extend_from_slice(&other.hidden_mounts);
+        self.props.extend_from_slice(&other.props);
+        self.build_fields.extend_from_slice(&other.build_fields);
+        self.cleared_runtime_flags.extend_from_slice(&other.cleared_runtime_flags);
+        self.blocked_packages.extend_from_slice(&other.blocked_packages);
+        if other.log.is_some() {
+            self.log = other.log;
+        }
+    }
+
+    /// All of `cleared_runtime_flags` together.
+    pub fn runtime_flags_to_clear(&self) -> RuntimeFlags {
+        self.cleared_runtime_flags.iter().fold(RuntimeFlags::empty(), |all, flags| all | *flags)
+    }
+
+    pub fn blocks_package(&self, package: &str) -> bool {
+        self.blocked_packages.iter().any(|p| p == package)
+    }
+}
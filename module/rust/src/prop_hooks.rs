@@ -0,0 +1,455 @@
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use std::sync::OnceLock;
+
+use libc::{c_char, c_int, c_void};
+
+use crate::{
+    config,
+    events::{self, Action},
+    plt::{load_orig as orig, PltHook},
+    props::{PropAction, PropMatch, PROP_VALUE_MAX},
+};
+
+static ORIG_GET: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_FIND: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_READ: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_READ_CALLBACK: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+static ORIG_FOREACH: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+
+/// `prop_info` is opaque to everyone but bionic.
+type PropInfo = c_void;
+type ReadCallback = extern "C" fn(*mut c_void, *const c_char, *const c_char, u32);
+type ForeachCallback = extern "C" fn(*const PropInfo, *mut c_void);
+
+type FindFn = extern "C" fn(*const c_char) -> *const PropInfo;
+type ReadFn = extern "C" fn(*const PropInfo, *mut c_char, *mut c_char) -> c_int;
+type ReadCallbackFn = extern "C" fn(*const PropInfo, ReadCallback, *mut c_void);
+type ForeachFn = extern "C" fn(ForeachCallback, *mut c_void) -> c_int;
+
+/// Legacy limit on property names, which `__system_property_read` copies into a buffer of this size.
+const PROP_NAME_MAX: usize = 32;
+
+/// Longest name a [FakeProp] can hold, NUL included.
+const FAKE_NAME_MAX: usize = 256;
+
+/// A stand-in `prop_info` for a spoofed property that does not exist on the device, so that
+/// `__system_property_find` can return something for it.
+///
+/// Our hooks recognize it by its address, but anything reading it without them, such as
+/// `__system_property_serial` or bionic calling itself, gets a valid short property in bionic's
+/// layout: the serial holds the value length in its top byte, followed by the value and the name.
+#[repr(C)]
+struct FakeProp {
+    serial: AtomicU32,
+    value: [c_char; PROP_VALUE_MAX],
+    name: [c_char; FAKE_NAME_MAX],
+}
+
+impl FakeProp {
+    /// `None` if either doesn't fit, or contains a NUL.
+    fn new(name: &str, value: &str) -> Option<FakeProp> {
+        let fits = name.len() < FAKE_NAME_MAX && value.len() < PROP_VALUE_MAX;
+        if !fits || name.contains('\0') || value.contains('\0') {
+            return None;
+        }
+        let serial = AtomicU32::new((value.len() as u32) << 24);
+        let mut fake = FakeProp { serial, value: [0; PROP_VALUE_MAX], name: [0; FAKE_NAME_MAX] };
+        for (dst, src) in fake.value.iter_mut().zip(value.bytes()) {
+            *dst = src as c_char;
+        }
+        for (dst, src) in fake.name.iter_mut().zip(name.bytes()) {
+            *dst = src as c_char;
+        }
+        Some(fake)
+    }
+
+    fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.name.as_ptr()) }
+    }
+
+    fn value(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.value.as_ptr()) }
+    }
+}
+
+static FAKE_PROPS: OnceLock<Vec<FakeProp>> = OnceLock::new();
+
+/// All property hooks, ready to be registered.
+pub fn plt_hooks() -> Vec<PltHook> {
+    vec![
+        PltHook { symbol: c"__system_property_get", replacement: hook_get as *mut (), orig: &ORIG_GET },
+        PltHook { symbol: c"__system_property_find", replacement: hook_find as *mut (), orig: &ORIG_FIND },
+        PltHook { symbol: c"__system_property_read", replacement: hook_read as *mut (), orig: &ORIG_READ },
+        PltHook {
+            symbol: c"__system_property_read_callback",
+            replacement: hook_read_callback as *mut (),
+            orig: &ORIG_READ_CALLBACK,
+        },
+        PltHook { symbol: c"__system_property_foreach", replacement: hook_foreach as *mut (), orig: &ORIG_FOREACH },
+    ]
+}
+
+/// Fake `prop_info`s for every exact-name spoof rule, created on first use. Prefix and regex
+/// rules can only spoof properties that exist.
+fn fake_props() -> &'static [FakeProp] {
+    FAKE_PROPS.get_or_init(|| {
+        config::active()
+            .profile
+            .props
+            .iter()
+            .filter(|rule| rule.kind == PropMatch::Exact)
+            .filter_map(|rule| match &rule.action {
+                PropAction::Spoof(value) => FakeProp::new(&rule.pattern, value),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+fn as_fake(pi: *const PropInfo) -> Option<&'static FakeProp> {
+    fake_props().iter().find(|fake| std::ptr::eq(*fake as *const FakeProp as *const PropInfo, pi))
+}
+
+/// The rule action for a property, if it isn't a passthrough.
+fn action_for(name: &CStr) -> Option<&'static PropAction> {
+    match config::active().props.lookup(name.to_str().ok()?)? {
+        PropAction::Passthrough => None,
+        action => Some(action),
+    }
+}
+
+/// [action_for] when a value is about to be served, logging and recording what is done to it.
+/// Plain lookups and enumerations don't count, as the app didn't see a value yet.
+fn serve(name: &CStr) -> Option<&'static PropAction> {
+    let action = action_for(name)?;
+    report(&name.to_string_lossy(), action);
+    Some(action)
+}
+
+fn report(name: &str, action: &PropAction) {
+    match action {
+        PropAction::Spoof(fake) => {
+            info!("Faking prop: {} -> {}", name, fake);
+            events::record("property", Action::Spoof, name);
+        }
+        PropAction::Hide => {
+            info!("Hiding prop: {}", name);
+            events::record("property", Action::Hide, name);
+        }
+        PropAction::Passthrough => {}
+    }
+}
+
+/// [report] for a fake property.
+fn report_fake(fake: &FakeProp) {
+    let value = fake.value().to_string_lossy();
+    report(&fake.name().to_string_lossy(), &PropAction::Spoof(value.into_owned()));
+}
+
+/// Write a property value into a caller-provided `PROP_VALUE_MAX` buffer the way bionic does,
+/// returning its length.
+///
+/// Values are checked to fit when the config is loaded, but are still cut off here rather than
+/// overflowing the caller's buffer.
+fn write_value(buf: *mut c_char, value: &[u8]) -> c_int {
+    if buf.is_null() {
+        return 0;
+    }
+    let len = value.len().min(PROP_VALUE_MAX - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, buf, len);
+        *buf.add(len) = 0;
+    }
+    len as c_int
+}
+
+/// Report an unset property: an empty, fully zeroed value buffer, so nothing the caller (or a
+/// previous lookup) left in it can leak through.
+fn clear_value(buf: *mut c_char) -> c_int {
+    if !buf.is_null() {
+        unsafe { std::ptr::write_bytes(buf, 0, PROP_VALUE_MAX) };
+    }
+    0
+}
+
+extern "C" fn hook_get(name: *const c_char, value: *mut c_char) -> c_int {
+    let Some(orig_fn) = orig::<extern "C" fn(*const c_char, *mut c_char) -> c_int>(&ORIG_GET) else {
+        return 0;
+    };
+
+    if !name.is_null() {
+        match serve(unsafe { CStr::from_ptr(name) }) {
+            Some(PropAction::Spoof(fake)) => return write_value(value, fake.as_bytes()),
+            Some(_) => return clear_value(value),
+            None => {}
+        }
+    }
+
+    orig_fn(name, value)
+}
+
+extern "C" fn hook_find(name: *const c_char) -> *const PropInfo {
+    let Some(orig_fn) = orig::<FindFn>(&ORIG_FIND) else {
+        return std::ptr::null();
+    };
+    if name.is_null() {
+        return orig_fn(name);
+    }
+
+    let name_cstr = unsafe { CStr::from_ptr(name) };
+    if let Some(PropAction::Hide) = action_for(name_cstr) {
+        return std::ptr::null();
+    }
+    let pi = orig_fn(name);
+    if !pi.is_null() {
+        return pi;
+    }
+    fake_props()
+        .iter()
+        .find(|fake| fake.name() == name_cstr)
+        .map_or(std::ptr::null(), |fake| fake as *const FakeProp as *const PropInfo)
+}
+
+extern "C" fn hook_read(pi: *const PropInfo, name: *mut c_char, value: *mut c_char) -> c_int {
+    if let Some(fake) = as_fake(pi) {
+        report_fake(fake);
+        if !name.is_null() {
+            write_name(name, fake.name());
+        }
+        return write_value(value, fake.value().to_bytes());
+    }
+    let Some(orig_fn) = orig::<ReadFn>(&ORIG_READ) else {
+        return 0;
+    };
+
+    // The caller may not want the name, but we need it to apply the rules
+    let mut name_buf = [0 as c_char; PROP_NAME_MAX];
+    let name_out = if name.is_null() { name_buf.as_mut_ptr() } else { name };
+    let len = orig_fn(pi, name_out, value);
+
+    // The copied name is cut off at `PROP_NAME_MAX`, and only does for the rules if it's all there is
+    let full_name = full_name(pi);
+    match serve(full_name.as_deref().unwrap_or(unsafe { CStr::from_ptr(name_out) })) {
+        Some(PropAction::Spoof(fake)) => write_value(value, fake.as_bytes()),
+        Some(_) => clear_value(value),
+        None => len,
+    }
+}
+
+/// The whole name of a property, which `__system_property_read_callback` passes on, if the
+/// original is known.
+fn full_name(pi: *const PropInfo) -> Option<CString> {
+    let read_callback = orig::<ReadCallbackFn>(&ORIG_READ_CALLBACK)?;
+
+    extern "C" fn copy_name(out: *mut c_void, name: *const c_char, _: *const c_char, _: u32) {
+        if !name.is_null() {
+            unsafe { *(out as *mut Option<CString>) = Some(CStr::from_ptr(name).to_owned()) };
+        }
+    }
+
+    let mut name = None;
+    read_callback(pi, copy_name, &mut name as *mut Option<CString> as *mut c_void);
+    name
+}
+
+fn write_name(buf: *mut c_char, name: &CStr) {
+    let bytes = name.to_bytes();
+    let len = bytes.len().min(PROP_NAME_MAX - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, buf, len);
+        *buf.add(len) = 0;
+    }
+}
+
+/// The caller's callback, passed through `__system_property_read_callback` as the cookie.
+struct ReadContext {
+    callback: ReadCallback,
+    cookie: *mut c_void,
+}
+
+extern "C" fn hook_read_callback(pi: *const PropInfo, callback: ReadCallback, cookie: *mut c_void) {
+    if let Some(fake) = as_fake(pi) {
+        report_fake(fake);
+        callback(cookie, fake.name.as_ptr(), fake.value.as_ptr(), fake.serial.load(Ordering::Acquire));
+        return;
+    }
+    let Some(orig_fn) = orig::<ReadCallbackFn>(&ORIG_READ_CALLBACK) else {
+        return;
+    };
+
+    let mut ctx = ReadContext { callback, cookie };
+    orig_fn(pi, filtered_read_callback, &mut ctx as *mut ReadContext as *mut c_void);
+}
+
+extern "C" fn filtered_read_callback(ctx: *mut c_void, name: *const c_char, value: *const c_char, serial: u32) {
+    let ctx = unsafe { &*(ctx as *const ReadContext) };
+    if name.is_null() {
+        return (ctx.callback)(ctx.cookie, name, value, serial);
+    }
+    match serve(unsafe { CStr::from_ptr(name) }) {
+        Some(PropAction::Spoof(fake)) => {
+            let fake = CString::new(fake.as_str()).unwrap_or_default();
+            (ctx.callback)(ctx.cookie, name, fake.as_ptr(), serial)
+        }
+        Some(_) => (ctx.callback)(ctx.cookie, name, c"".as_ptr(), serial),
+        None => (ctx.callback)(ctx.cookie, name, value, serial),
+    }
+}
+
+/// The caller's callback, passed through `__system_property_foreach` as the cookie.
+struct ForeachContext {
+    callback: ForeachCallback,
+    cookie: *mut c_void,
+    read_callback: Option<ReadCallbackFn>,
+}
+
+extern "C" fn hook_foreach(callback: ForeachCallback, cookie: *mut c_void) -> c_int {
+    let Some(orig_fn) = orig::<ForeachFn>(&ORIG_FOREACH) else {
+        return -1;
+    };
+
+    let mut ctx = ForeachContext { callback, cookie, read_callback: orig(&ORIG_READ_CALLBACK) };
+    let ret = orig_fn(filtered_foreach, &mut ctx as *mut ForeachContext as *mut c_void);
+
+    // Spoofed properties that don't exist must show up in enumerations as well
+    if ret == 0 {
+        for fake in fake_props() {
+            let real = orig::<FindFn>(&ORIG_FIND).map_or(std::ptr::null(), |find| find(fake.name.as_ptr()));
+            if real.is_null() {
+                callback(fake as *const FakeProp as *const PropInfo, cookie);
+            }
+        }
+    }
+    ret
+}
+
+extern "C" fn filtered_foreach(pi: *const PropInfo, ctx: *mut c_void) {
+    let ctx = unsafe { &*(ctx as *const ForeachContext) };
+
+    if let Some(read_callback) = ctx.read_callback {
+        extern "C" fn is_hidden(hidden: *mut c_void, name: *const c_char, _: *const c_char, _: u32) {
+            if !name.is_null() {
+                let hide = matches!(action_for(unsafe { CStr::from_ptr(name) }), Some(PropAction::Hide));
+                unsafe { *(hidden as *mut bool) = hide };
+            }
+        }
+
+        let mut hidden = false;
+        read_callback(pi, is_hidden, &mut hidden as *mut bool as *mut c_void);
+        if hidden {
+            return;
+        }
+    }
+
+    (ctx.callback)(pi, ctx.cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stale_buffer() -> [c_char; PROP_VALUE_MAX] {
+        [b'?' as c_char; PROP_VALUE_MAX]
+    }
+
+    fn as_str(buf: &[c_char]) -> &str {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap()
+    }
+
+    #[test]
+    fn spoofed_values_are_nul_terminated() {
+        let mut buf = stale_buffer();
+        assert_eq!(write_value(buf.as_mut_ptr(), b"green"), 5);
+        assert_eq!(as_str(&buf), "green");
+    }
+
+    #[test]
+    fn spoofed_values_never_overflow() {
+        let mut buf = [stale_buffer(), stale_buffer()].concat();
+        let long = [b'x'; PROP_VALUE_MAX + 10];
+        assert_eq!(write_value(buf.as_mut_ptr(), &long), (PROP_VALUE_MAX - 1) as c_int);
+        assert_eq!(as_str(&buf).len(), PROP_VALUE_MAX - 1);
+        assert!(buf[PROP_VALUE_MAX..].iter().all(|&c| c == b'?' as c_char));
+    }
+
+    #[test]
+    fn fake_props_use_the_bionic_layout() {
+        assert_eq!(std::mem::offset_of!(FakeProp, value), 4);
+        assert_eq!(std::mem::offset_of!(FakeProp, name), 4 + PROP_VALUE_MAX);
+
+        let fake = FakeProp::new("ro.boot.verifiedbootstate", "green").unwrap();
+        let raw = &fake as *const FakeProp as *const u8;
+        // What `__system_property_serial` and bionic's own readers look at
+        let serial = unsafe { std::ptr::read(raw as *const u32) };
+        assert_eq!(serial >> 24, 5);
+        assert_eq!(serial & (1 << 16), 0, "not a long property");
+        assert_eq!(unsafe { CStr::from_ptr(raw.add(4) as *const c_char) }, c"green");
+        assert_eq!(unsafe { CStr::from_ptr(raw.add(4 + PROP_VALUE_MAX) as *const c_char) }, fake.name());
+
+        assert!(FakeProp::new(&"x".repeat(FAKE_NAME_MAX), "1").is_none());
+        assert!(FakeProp::new("ro.x", &"x".repeat(PROP_VALUE_MAX)).is_none());
+    }
+
+    /// A property as the stubbed bionic functions below see it.
+    struct TestProp {
+        name: &'static CStr,
+        value: &'static CStr,
+    }
+
+    extern "C" fn stub_read(pi: *const PropInfo, name: *mut c_char, value: *mut c_char) -> c_int {
+        let prop = unsafe { &*(pi as *const TestProp) };
+        if !name.is_null() {
+            write_name(name, prop.name);
+        }
+        write_value(value, prop.value.to_bytes())
+    }
+
+    extern "C" fn stub_read_callback(pi: *const PropInfo, callback: ReadCallback, cookie: *mut c_void) {
+        let prop = unsafe { &*(pi as *const TestProp) };
+        callback(cookie, prop.name.as_ptr(), prop.value.as_ptr(), 0);
+    }
+
+    #[test]
+    fn long_names_are_matched_in_full_by_read() {
+        let _lock = events::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        config::install_for_tests();
+        ORIG_READ.store(stub_read as *mut (), Ordering::Relaxed);
+        ORIG_READ_CALLBACK.store(stub_read_callback as *mut (), Ordering::Relaxed);
+
+        let long = TestProp { name: c"persist.vendor.geoink.test.long.name", value: c"real" };
+        assert!(long.name.to_bytes().len() >= PROP_NAME_MAX);
+        let other = TestProp { name: c"persist.vendor.geoink.test.long.name2", value: c"real" };
+
+        for name_buf in [true, false] {
+            let mut name = [0 as c_char; PROP_NAME_MAX];
+            let name_ptr = if name_buf { name.as_mut_ptr() } else { std::ptr::null_mut() };
+            let mut value = stale_buffer();
+            let pi = &long as *const TestProp as *const PropInfo;
+            assert_eq!(hook_read(pi, name_ptr, value.as_mut_ptr()), 7);
+            assert_eq!(as_str(&value), "spoofed");
+            if name_buf {
+                // The caller still gets the name the way bionic hands it out
+                assert_eq!(as_str(&name), &long.name.to_str().unwrap()[..PROP_NAME_MAX - 1]);
+            }
+        }
+
+        // Sharing the first `PROP_NAME_MAX` characters doesn't make it the same property
+        let mut value = stale_buffer();
+        let pi = &other as *const TestProp as *const PropInfo;
+        assert_eq!(hook_read(pi, std::ptr::null_mut(), value.as_mut_ptr()), 4);
+        assert_eq!(as_str(&value), "real");
+
+        let (recorded, _) = events::drain();
+        let subjects: Vec<_> = recorded.iter().map(|e| (e.action, e.subject.as_str())).collect();
+        assert_eq!(subjects, [(Action::Spoof, long.name.to_str().unwrap()); 2]);
+    }
+
+    #[test]
+    fn hidden_values_are_zeroed() {
+        let mut buf = [stale_buffer(), stale_buffer()].concat();
+        assert_eq!(clear_value(buf.as_mut_ptr()), 0);
+        assert!(buf[..PROP_VALUE_MAX].iter().all(|&c| c == 0));
+        assert!(buf[PROP_VALUE_MAX..].iter().all(|&c| c == b'?' as c_char));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use regex_lite::Regex;
+
+/// Size of the value buffer passed to `__system_property_get`, including the terminating NUL.
+pub const PROP_VALUE_MAX: usize = 92;
+
+/// How a [PropRule] pattern is compared against a property name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropMatch {
+    Exact,
+    Prefix,
+    /// An unanchored regular expression, so `lineage` matches `ro.lineage.version`.
+    Regex,
+}
+
+/// What happens to a property matched by a [PropRule].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PropAction {
+    /// Report this value instead of the real one.
+    Spoof(String),
+    /// Report the property as unset.
+    Hide,
+    /// Report the real value, overriding any later rule.
+    Passthrough,
+}
+
+/// A single property rule, written in the config as `[<kind>:]<pattern> <action>`, where the
+/// action is `spoof <value>`, `hide` or `pass`. A pattern without a kind is an exact name.
+///
+/// ```text
+/// prop = ro.boot.verifiedbootstate spoof green
+/// prop = prefix:ro.lineage. hide
+/// prop = regex:^ro\.build\.version\.(sdk|release)$ pass
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PropRule {
+    pub kind: PropMatch,
+    pub pattern: String,
+    pub action: PropAction,
+}
+
+/// Properties that leak an unlocked bootloader or a non-production build, and the values a
+/// locked retail device reports for them. Enabled with `preset = verified-boot`.
+const VERIFIED_BOOT_PRESET: &[(&str, &str)] = &[
+    ("ro.boot.verifiedbootstate", "green"),
+    ("vendor.boot.verifiedbootstate", "green"),
+    ("ro.boot.flash.locked", "1"),
+    ("ro.boot.vbmeta.device_state", "locked"),
+    ("ro.boot.veritymode", "enforcing"),
+    ("ro.debuggable", "0"),
+    ("ro.secure", "1"),
+    ("ro.build.tags", "release-keys"),
+    ("ro.build.type", "user"),
+];
+
+impl PropRule {
+    pub fn parse(value: &str) -> Result<PropRule, String> {
+        let (matcher, action) = value
+            .split_once(char::is_whitespace)
+            .ok_or("expected `<pattern> spoof <value>`, `<pattern> hide` or `<pattern> pass`")?;
+        let action = action.trim();
+        let action = match action.split_once(char::is_whitespace) {
+            Some(("spoof", fake)) => PropAction::Spoof(check_value(fake.trim())?.to_string()),
+            None if action == "hide" => PropAction::Hide,
+            None if action == "pass" => PropAction::Passthrough,
+            _ => return Err(format!("unknown property action `{}`", action)),
+        };
+
+        let (kind, pattern) = match matcher.split_once(':') {
+            Some(("exact", p)) => (PropMatch::Exact, p),
+            Some(("prefix", p)) => (PropMatch::Prefix, p),
+            Some(("regex", p)) => (PropMatch::Regex, p),
+            Some((kind, _)) => return Err(format!("unknown property rule kind `{}`", kind)),
+            None => (PropMatch::Exact, matcher),
+        };
+        if pattern.is_empty() {
+            return Err("empty property pattern".to_string());
+        }
+        if kind == PropMatch::Regex {
+            Regex::new(pattern).map_err(|e| format!("bad regex `{}`: {}", pattern, e))?;
+        }
+
+        Ok(PropRule { kind, pattern: pattern.to_string(), action })
+    }
+
+    pub fn spoof(name: &str, value: &str) -> Result<PropRule, String> {
+        Ok(PropRule {
+            kind: PropMatch::Exact,
+            pattern: name.to_string(),
+            action: PropAction::Spoof(check_value(value)?.to_string()),
+        })
+    }
+
+    /// The rules of a named preset, or `None` if there is no such preset.
+    pub fn preset(name: &str) -> Option<Vec<PropRule>> {
+        match name {
+            "verified-boot" => Some(
+                VERIFIED_BOOT_PRESET
+                    .iter()
+                    .map(|(name, value)| PropRule::spoof(name, value).expect("preset values must fit"))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Make sure a spoofed value fits the buffer of `__system_property_get`, so the hooks never
+/// have to truncate it.
+fn check_value(value: &str) -> Result<&str, String> {
+    if value.len() >= PROP_VALUE_MAX {
+        return Err(format!("spoofed value is longer than {} bytes", PROP_VALUE_MAX - 1));
+    }
+    if value.contains('\0') {
+        return Err("spoofed value contains a NUL byte".to_string());
+    }
+    Ok(value)
+}
+
+/// A list of [PropRule]s indexed for lookups from the property hooks. Like
+/// [PathRules](crate::paths::PathRules), it is built once and then only read.
+#[derive(Debug, Default)]
+pub struct PropRules {
+    exact: HashMap<String, usize>,
+    prefix: Vec<(String, usize)>,
+    regex: Vec<(Regex, usize)>,
+    actions: Vec<PropAction>,
+}
+
+impl PropRules {
+    pub fn new(rules: &[PropRule]) -> PropRules {
+        let mut set = PropRules::default();
+        for (idx, rule) in rules.iter().enumerate() {
+            set.actions.push(rule.action.clone());
+            match rule.kind {
+                PropMatch::Exact => {
+                    set.exact.entry(rule.pattern.clone()).or_insert(idx);
+                }
+                PropMatch::Prefix => set.prefix.push((rule.pattern.clone(), idx)),
+                // Patterns were validated when the config was parsed
+                PropMatch::Regex => set.regex.extend(Regex::new(&rule.pattern).ok().map(|r| (r, idx))),
+            }
+        }
+        set
+    }
+
+    /// The action of the first rule matching `name`, if any.
+    pub fn lookup(&self, name: &str) -> Option<&PropAction> {
+        let prefix = self.prefix.iter().filter(|(p, _)| name.starts_with(p.as_str()));
+        let regex = self.regex.iter().filter(|(r, _)| r.is_match(name));
+        let first = prefix
+            .map(|(_, idx)| *idx)
+            .chain(regex.map(|(_, idx)| *idx))
+            .chain(self.exact.get(name).copied())
+            .min()?;
+        Some(&self.actions[first])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> PropRules {
+        PropRules::new(&rules.iter().map(|r| PropRule::parse(r).unwrap()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(&[
+            "ro.lineage.build.version pass",
+            "prefix:ro.lineage. hide",
+            "regex:^ro\\.boot\\.(verified|realme)bootstate$ spoof green",
+        ]);
+        assert_eq!(rules.lookup("ro.lineage.build.version"), Some(&PropAction::Passthrough));
+        assert_eq!(rules.lookup("ro.lineage.device"), Some(&PropAction::Hide));
+        assert_eq!(
+            rules.lookup("ro.boot.realmebootstate"),
+            Some(&PropAction::Spoof("green".to_string()))
+        );
+        assert_eq!(rules.lookup("ro.boot.verifiedbootstate.x"), None);
+        assert_eq!(rules.lookup("ro.product.model"), None);
+    }
+
+    #[test]
+    fn rule_parsing() {
+        let rule = PropRule::parse("ro.build.fingerprint spoof google/a b/c").unwrap();
+        assert_eq!(rule.action, PropAction::Spoof("google/a b/c".to_string()));
+        assert!(PropRule::parse("ro.secure").is_err());
+        assert!(PropRule::parse("ro.secure drop").is_err());
+        assert!(PropRule::parse("glob:ro.* hide").is_err());
+        assert!(PropRule::parse("regex:ro.(boot hide").is_err());
+        assert!(PropRule::preset("verified-boot").unwrap().len() >= 7);
+    }
+
+    #[test]
+    fn spoofed_values_must_fit_prop_value_max() {
+        let longest = "x".repeat(PROP_VALUE_MAX - 1);
+        assert!(PropRule::parse(&format!("ro.x spoof {}", longest)).is_ok());
+        assert!(PropRule::parse(&format!("ro.x spoof {}x", longest)).is_err());
+        assert!(PropRule::spoof("ro.x", &format!("{}x", longest)).is_err());
+    }
+}